@@ -0,0 +1,276 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::collections::BTreeMap;
+
+use glob::Pattern;
+
+use config::{ConfigDefinition, ConfigGroup};
+use execution::Execution;
+use history;
+use keys;
+use mux;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    Exited(i32),
+    Signaled(i32),
+    Failed(String),
+    Skipped(String),
+}
+
+impl Status {
+    fn is_success(&self) -> bool {
+        *self == Status::Exited(0)
+    }
+
+    /// Exit code to record in the history, if the command was run at all.
+    fn history_status(&self) -> Option<Option<i32>> {
+        match *self {
+            Status::Exited(code) => Some(Some(code)),
+            Status::Signaled(_) | Status::Failed(_) => Some(None),
+            Status::Skipped(_) => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Status::Exited(code) => format!("exit code {}", code),
+            Status::Signaled(signal) => format!("killed by signal {}", signal),
+            Status::Failed(ref e) => format!("failed to run: {}", e),
+            Status::Skipped(ref reason) => format!("skipped: {}", reason),
+        }
+    }
+}
+
+/// Where the prefixed output lines of the hosts go.
+#[derive(Clone)]
+enum Sink {
+    /// Standard output and error of sshc
+    Std,
+    /// Lines collected together with whether they came from stderr, for tests
+    Collect(Arc<Mutex<Vec<(bool, String)>>>),
+}
+
+impl Sink {
+    fn write_line(&self, line: Vec<u8>, to_stderr: bool) {
+        match *self {
+            Sink::Std => {
+                let _ = if to_stderr {
+                    io::stderr().write_all(&line)
+                } else {
+                    io::stdout().write_all(&line)
+                };
+            }
+            Sink::Collect(ref lines) =>
+                lines.lock().unwrap().push((to_stderr, String::from_utf8_lossy(&line).into_owned())),
+        }
+    }
+}
+
+/// Finds definitions matching the target, which can be a profile, a group or a glob pattern
+/// matching full profile paths, like `prod.*.web`.
+pub fn select_targets(root: &ConfigGroup, target: &str) -> Result<Vec<(String, ConfigDefinition)>, String> {
+    let definitions = root.iter_definitions();
+
+    let selected: Vec<_> = if target.contains(|c| c == '*' || c == '?' || c == '[') {
+        let pattern = Pattern::new(target).map_err(|e| format!("invalid pattern {}: {}", target, e))?;
+        definitions.into_iter().filter(|&(ref path, _)| pattern.matches(path)).collect()
+    } else {
+        let prefix = format!("{}.", target);
+        definitions.into_iter().filter(|&(ref path, _)| path == target || path.starts_with(&prefix)).collect()
+    };
+
+    if selected.is_empty() {
+        return Err(format!("no profiles match {}", target));
+    }
+
+    Ok(selected.into_iter().map(|(path, definition)| (path, definition.clone())).collect())
+}
+
+/// Runs the command on all targets with at most `jobs` connections at a time and prints the summary.
+///
+/// The command is a single shell string which is interpreted by the shell of every host, like
+/// the command of ssh. Returns `true` if the command succeeded everywhere.
+pub fn run(targets: Vec<(String, ConfigDefinition)>, command: &str, jobs: usize) -> bool {
+    let outcomes = run_all(targets, command, jobs, Sink::Std);
+
+    let width = outcomes.iter().map(|&(ref path, _)| path.len()).max().unwrap_or(0);
+    println!();
+    for &(ref path, ref status) in &outcomes {
+        println!("{:width$}  {}", path, status.describe(), width = width);
+        if let Some(code) = status.history_status() {
+            history::record(&history::Entry::new(path.clone(), false, code));
+        }
+    }
+
+    outcomes.iter().all(|&(_, ref status)| status.is_success())
+}
+
+fn run_all(targets: Vec<(String, ConfigDefinition)>, command: &str, jobs: usize, sink: Sink) -> Vec<(String, Status)> {
+    let mut outcomes = BTreeMap::new();
+
+    let mut tasks = Vec::new();
+    for (path, definition) in targets {
+        let definition = match definition.instantiate(&BTreeMap::new()) {
+            Ok(definition) => definition,
+            Err(e) => {
+                outcomes.insert(path, Status::Skipped(e.to_string()));
+                continue;
+            }
+        };
+
+        let warnings = keys::warnings(&keys::check_chain(&definition.chain));
+        let multiplex = definition.multiplex;
+        let mut e = Execution::from(definition);
+        if !e.accepts_command() {
+            outcomes.insert(path, Status::Skipped("the profile does not run commands non-interactively".into()));
+            continue;
+        }
+        for warning in warnings {
+            sink.write_line(format!("[{}] {}\n", path, warning).into_bytes(), true);
+        }
+        if multiplex {
+            mux::ensure_control_dir();
+            mux::register(&path);
+        }
+
+        let args = e.remote_command_args(command);
+        tasks.push((path, args));
+    }
+
+    let tasks = Arc::new(Mutex::new(tasks.into_iter()));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..jobs.max(1)).map(|_| {
+        let tasks = tasks.clone();
+        let tx = tx.clone();
+        let sink = sink.clone();
+        thread::spawn(move || loop {
+            let task = tasks.lock().unwrap().next();
+            match task {
+                Some((path, args)) => {
+                    let status = run_one(&path, &args, &sink);
+                    tx.send((path, status)).unwrap();
+                }
+                None => break,
+            }
+        })
+    }).collect();
+    drop(tx);
+
+    outcomes.extend(rx);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    outcomes.into_iter().collect()
+}
+
+fn run_one(path: &str, args: &[String], sink: &Sink) -> Status {
+    let mut child = match Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn() {
+        Ok(child) => child,
+        Err(e) => return Status::Failed(e.to_string()),
+    };
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let readers = vec![
+        spawn_prefixer(path.into(), stdout, sink.clone(), false),
+        spawn_prefixer(path.into(), stderr, sink.clone(), true),
+    ];
+
+    let status = child.wait();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    match status {
+        Ok(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => Status::Exited(code),
+            (None, Some(signal)) => Status::Signaled(signal),
+            (None, None) => Status::Failed("unknown exit status".into()),
+        },
+        Err(e) => Status::Failed(e.to_string()),
+    }
+}
+
+/// Copies lines from `input` to stdout or stderr, prefixing each of them with the profile path.
+fn spawn_prefixer<R: Read + Send + 'static>(path: String, input: R, sink: Sink, to_stderr: bool) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(input).split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            // Output of different hosts is interleaved only at line boundaries
+            let mut buf = format!("[{}] ", path).into_bytes();
+            buf.extend(line);
+            buf.push(b'\n');
+
+            sink.write_line(buf, to_stderr);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{SingleJump, State};
+    use testing::TempDir;
+
+    #[test]
+    fn test_run_all() {
+        // A stand-in for ssh which prints its arguments one per line and fails for hosts named "broken"
+        let dir = TempDir::new("batch");
+        let ssh = dir.script("ssh", "for arg in \"$@\"; do echo \"<$arg>\"; done\n\
+                                     echo oops >&2\n\
+                                     case \"$*\" in *broken*) exit 3;; esac\n");
+        let jump = |host: &str| SingleJump { ssh_path: Some(ssh.display().to_string()), ..SingleJump::new(host.into()) };
+
+        let mut tunnel = ConfigDefinition { chain: vec![jump("tunnel")], ..ConfigDefinition::default() };
+        tunnel.chain[0].no_command = State::Enabled(());
+
+        let targets = vec![
+            ("a.ok".to_owned(), ConfigDefinition { chain: vec![jump("ok1"), jump("ok2")], ..ConfigDefinition::default() }),
+            ("a.broken".to_owned(), ConfigDefinition { chain: vec![jump("broken")], ..ConfigDefinition::default() }),
+            ("a.tunnel".to_owned(), tunnel),
+        ];
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let outcomes = run_all(targets, "echo 'hello world'", 2, Sink::Collect(lines.clone()));
+        assert_eq!(outcomes, vec![
+            ("a.broken".to_owned(), Status::Exited(3)),
+            ("a.ok".to_owned(), Status::Exited(0)),
+            ("a.tunnel".to_owned(), Status::Skipped("the profile does not run commands non-interactively".into())),
+        ]);
+
+        // The command stays one argument, quoted once more for the shell of the intermediate host
+        let ssh = ssh.display().to_string();
+        let lines = lines.lock().unwrap();
+        let output = |path: &str, to_stderr: bool| -> Vec<String> {
+            let prefix = format!("[{}] ", path);
+            lines.iter()
+                .filter(|&&(stderr, ref line)| stderr == to_stderr && line.starts_with(&prefix))
+                .map(|&(_, ref line)| line.clone())
+                .collect()
+        };
+        assert_eq!(output("a.ok", false), vec![
+            "[a.ok] <ok1>\n".to_owned(),
+            format!("[a.ok] <{}>\n", ssh),
+            "[a.ok] <ok2>\n".into(),
+            "[a.ok] <'echo '\\''hello world'\\'''>\n".into(),
+        ]);
+        assert_eq!(output("a.broken", false), vec!["[a.broken] <broken>\n".to_owned(), "[a.broken] <echo 'hello world'>\n".into()]);
+        assert_eq!(output("a.ok", true), vec!["[a.ok] oops\n".to_owned()]);
+    }
+}
@@ -1,11 +1,16 @@
+use std::env;
 use std::fs::File;
 use std::path::Path;
 use std::collections::BTreeMap;
 use std::io::{self, Read};
+use std::time::Duration;
 
+use serde_json;
+use serde_yaml;
 use toml;
-use toml::Value;
-use toml::value::{Table, Array};
+
+use inventory;
+use value::{self, Value, Table, Array};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum State<T> {
@@ -20,10 +25,91 @@ pub struct SingleJump {
     pub port: Option<u16>,
     pub user: Option<String>,
     pub key: Option<String>,
+    /// Which machine the key file is on; unset means the machine the jump is connected from
+    pub key_location: Option<KeyLocation>,
     pub tunnel: State<Tunnel>,
     pub verbose: bool,
     pub agent_passthrough: State<()>,
     pub no_command: State<()>,
+    pub transport: Option<Transport>,
+    pub ssh_path: Option<String>,
+    /// Variables set on the host with `SetEnv`
+    pub set_env: BTreeMap<String, String>,
+    /// Patterns of local variables sent to the host with `SendEnv`
+    pub send_env: Vec<String>,
+}
+
+impl SingleJump {
+    pub fn new(host: String) -> SingleJump {
+        SingleJump {
+            host,
+            port: None,
+            user: None,
+            key: None,
+            key_location: None,
+            tunnel: State::Unset,
+            verbose: false,
+            agent_passthrough: State::Unset,
+            no_command: State::Unset,
+            transport: None,
+            ssh_path: None,
+            set_env: BTreeMap::new(),
+            send_env: Vec::new(),
+        }
+    }
+
+    /// Returns the `[user@]host` destination of the jump as passed to ssh.
+    pub fn destination(&self) -> String {
+        match self.user {
+            Some(ref user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Transport {
+    Ssh,
+    Mosh,
+    Autossh,
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transport::Ssh => "ssh",
+            Transport::Mosh => "mosh",
+            Transport::Autossh => "autossh",
+        }
+    }
+}
+
+/// Where the key file of a jump is.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyLocation {
+    /// On the local machine, so the jump is connected from there through `ProxyCommand`
+    Local,
+    /// On the previous host of the chain, where the nested ssh of the jump runs
+    Remote,
+}
+
+impl KeyLocation {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeyLocation::Local => "local",
+            KeyLocation::Remote => "remote",
+        }
+    }
+}
+
+/// Returns the index of the last jump with a key on the local machine, or 0 if there is none.
+///
+/// That jump is the first one run by the local ssh, and all jumps before it are connected through
+/// its `ProxyCommand`, so that their keys are local as well.
+pub fn proxied_jumps(chain: &[SingleJump]) -> usize {
+    chain.iter()
+        .rposition(|jump| jump.key.is_some() && jump.key_location == Some(KeyLocation::Local))
+        .unwrap_or(0)
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
@@ -34,9 +120,28 @@ pub struct Tunnel {
     pub remote_host: Option<String>
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct ConfigDefinition {
     pub chain: Vec<SingleJump>,
+    pub params: BTreeMap<String, Param>,
+    pub multiplex: bool,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Environment label, like `prod` or `staging`.
+    pub env: Option<String>,
+    /// Whether connecting requires a confirmation; defaults to `true` for production environments.
+    pub confirm: Option<bool>,
+    /// Whether the session is recorded to a file
+    pub record: bool,
+    /// Directory for recordings, instead of the default one
+    pub record_dir: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct Param {
+    pub default: Option<String>,
+    #[serde(default)]
+    pub choices: Vec<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -50,6 +155,65 @@ pub enum ConfigItem {
     Subgroup(ConfigGroup),
 }
 
+impl ConfigGroup {
+    /// Returns all definitions in this group and its subgroups together with their full dotted paths.
+    pub fn iter_definitions(&self) -> Vec<(String, &ConfigDefinition)> {
+        fn collect<'a>(group: &'a ConfigGroup, prefix: &str, result: &mut Vec<(String, &'a ConfigDefinition)>) {
+            for (k, item) in &group.definitions {
+                let path = if prefix.is_empty() { k.clone() } else { format!("{}.{}", prefix, k) };
+                match *item {
+                    ConfigItem::Definition(ref definition) => result.push((path, definition)),
+                    ConfigItem::Subgroup(ref group) => collect(group, &path, result),
+                }
+            }
+        }
+
+        let mut result = Vec::new();
+        collect(self, "", &mut result);
+        result
+    }
+
+    /// Returns the item with the given path, split into segments.
+    pub fn get_path<S: AsRef<str>>(&self, path: &[S]) -> Result<&ConfigItem> {
+        let segments: Vec<&str> = path.iter().map(AsRef::as_ref).collect();
+
+        let mut group = self;
+        let mut item = None;
+        for (i, segment) in segments.iter().enumerate() {
+            if let Some(&ConfigItem::Definition(_)) = item {
+                return Err(ErrorKind::ProfileUsedAsGroup(segments[..i].join("."), segment.to_string()).into());
+            }
+
+            let found = match group.definitions.get(*segment) {
+                Some(found) => found,
+                None => return Err(ErrorKind::NotFound(segments[..i + 1].join(".")).into()),
+            };
+            if let ConfigItem::Subgroup(ref subgroup) = *found {
+                group = subgroup;
+            }
+            item = Some(found);
+        }
+
+        item.ok_or_else(|| "empty path".into())
+    }
+
+    /// Returns the definition with the given dotted path, like `work.db`.
+    pub fn get_definition(&self, path: &str) -> Result<&ConfigDefinition> {
+        let segments: Vec<_> = path.split('.').collect();
+        match *self.get_path(&segments)? {
+            ConfigItem::Definition(ref definition) => Ok(definition),
+            ConfigItem::Subgroup(_) => Err(ErrorKind::GroupNotProfile(path.into()).into()),
+        }
+    }
+
+    /// Returns the first definition, in the order of `iter_definitions`, which matches the predicate.
+    pub fn find<P>(&self, mut predicate: P) -> Option<(String, &ConfigDefinition)>
+        where P: FnMut(&str, &ConfigDefinition) -> bool
+    {
+        self.iter_definitions().into_iter().find(|&(ref path, definition)| predicate(path, definition))
+    }
+}
+
 impl ConfigItem {
     pub fn is_group(&self) -> bool {
         match *self {
@@ -59,15 +223,161 @@ impl ConfigItem {
     }
 }
 
+impl ConfigDefinition {
+    /// Returns `true` if the user must confirm connecting to this profile.
+    pub fn requires_confirmation(&self) -> bool {
+        self.confirm.unwrap_or_else(|| match self.env.as_ref().map(|e| e.as_str()) {
+            Some("prod") | Some("production") => true,
+            _ => false,
+        })
+    }
+
+    /// Substitutes `{param}` placeholders in the chain with the given values.
+    ///
+    /// Parameters without a value fall back to their defaults. The resulting definition has
+    /// no parameters.
+    pub fn instantiate(&self, values: &BTreeMap<String, String>) -> Result<ConfigDefinition> {
+        if let Some(name) = values.keys().find(|k| !self.params.contains_key(*k)) {
+            return Err(format!("unknown parameter {}", name).into());
+        }
+
+        let mut resolved = BTreeMap::new();
+        for (name, param) in &self.params {
+            let value = match values.get(name).or(param.default.as_ref()) {
+                Some(value) => value,
+                None => return Err(format!("parameter {} is not set", name).into()),
+            };
+            if !param.choices.is_empty() && !param.choices.contains(value) {
+                return Err(format!(
+                    "invalid value {} for parameter {}, expected one of: {}",
+                    value, name, param.choices.join(", ")
+                ).into());
+            }
+            resolved.insert(name.as_str(), value.as_str());
+        }
+
+        let substitute = |s: &mut String| *s = substitute_params(s, |name| resolved.get(name).cloned());
+
+        let mut definition = self.clone();
+        definition.params.clear();
+        for jump in &mut definition.chain {
+            substitute(&mut jump.host);
+            if let Some(ref mut user) = jump.user { substitute(user); }
+            if let Some(ref mut key) = jump.key { substitute(key); }
+            for value in jump.set_env.values_mut() { substitute(value); }
+            if let State::Enabled(ref mut tunnel) = jump.tunnel {
+                if let Some(ref mut host) = tunnel.local_host { substitute(host); }
+                if let Some(ref mut host) = tunnel.remote_host { substitute(host); }
+            }
+        }
+
+        Ok(definition)
+    }
+}
+
+/// Replaces `{name}` placeholders for which `lookup` returns a value, leaving everything else intact.
+///
+/// A `{` right after `$` is not a placeholder, so that `$${name}`, which is unescaped to `${name}`
+/// when variables are expanded, stays literal.
+fn substitute_params<'a, F>(s: &str, lookup: F) -> String where F: Fn(&str) -> Option<&'a str> {
+    let mut result = String::with_capacity(s.len());
+
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let escaped = rest[..start].ends_with('$');
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}')
+            .and_then(|end| if escaped { None } else { Some((end, &rest[1..end])) })
+            .and_then(|(end, name)| lookup(name).map(|v| (end, v)));
+        match value {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// Returns names of all `{name}` placeholders in the given string, skipping literal `${name}`.
+fn param_references(s: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let escaped = rest[..start].ends_with('$');
+        rest = &rest[start + 1..];
+        if escaped {
+            continue;
+        }
+        if let Some(end) = rest.find('}') {
+            let name = &rest[..end];
+            if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                result.push(name);
+                rest = &rest[end + 1..];
+            }
+        }
+    }
+
+    result
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Config {
     pub root: ConfigGroup,
 }
 
+/// Variables defined in the `[vars]` section of the configuration file.
+pub type Vars = BTreeMap<String, String>;
+
 error_chain! {
     foreign_links {
         Io(io::Error);
         Toml(toml::de::Error);
+        Json(serde_json::Error);
+        Yaml(serde_yaml::Error);
+    }
+
+    errors {
+        NotFound(path: String) {
+            description("profile or group not found")
+            display("{} does not exist", path)
+        }
+        GroupNotProfile(path: String) {
+            description("group used as a profile")
+            display("{} is a group, not a profile", path)
+        }
+        ProfileUsedAsGroup(path: String, segment: String) {
+            description("profile used as a group")
+            display("{} is a profile, not a group, so it has no {}", path, segment)
+        }
+    }
+}
+
+/// Format of the configuration file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Picks the format by the file extension; files without a known extension are TOML.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
     }
 }
 
@@ -77,20 +387,135 @@ pub fn load(path: &Path) -> Result<Config> {
     let mut data = String::new();
     f.read_to_string(&mut data)?;
 
-    load_from_string(&data)
+    load_from_string_as(&data, Format::from_path(path))
+}
+
+pub fn load_from_string(s: &str) -> Result<Config> {
+    load_from_string_as(s, Format::Toml)
 }
 
-fn load_from_string(s: &str) -> Result<Config> {
-    let table = match s.parse::<Value>()? {
+pub fn load_from_string_as(s: &str, format: Format) -> Result<Config> {
+    let value = match format {
+        Format::Toml => Value::from(s.parse::<toml::Value>()?),
+        Format::Json => Value::from(serde_json::from_str::<serde_json::Value>(s)?),
+        Format::Yaml => value::from_yaml(serde_yaml::from_str(s)?)?,
+    };
+    let mut table = match value {
         Value::Table(table) => table,
-        _ => unreachable!(),  // cannot happen
+        other => return Err(format!("expected table at the top level, got {}", other.type_str()).into()),
+    };
+    let vars = match table.remove("vars") {
+        Some(Value::Table(vars)) => read_vars(vars)?,
+        None => Vars::new(),
+        Some(other) =>
+            return Err(format!("vars is invalid: expected table, got {}", other.type_str()).into()),
     };
-    let root = read_config_group("".into(), table)?;
+    let root = read_config_group(&vars, "".into(), table, false)?;
 
     Ok(Config { root, })
 }
 
-fn read_config_group(path: String, table: Table) -> Result<ConfigGroup> {
+fn read_vars(table: Table) -> Result<Vars> {
+    let no_vars = Vars::new();
+
+    let mut vars = Vars::new();
+    for (k, v) in table {
+        match v {
+            // Variables may only refer to the environment, but not to each other
+            Value::String(s) => match expand_vars(&s, &no_vars) {
+                Ok(s) => { vars.insert(k, s); }
+                Err(e) => return Err(format!("variable {} is invalid: {}", k, e).into()),
+            },
+            other =>
+                return Err(format!("variable {} is invalid: expected string, got {}", k, other.type_str()).into()),
+        }
+    }
+
+    Ok(vars)
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` references in the given string.
+///
+/// Variables are looked up in `vars` first and then in the process environment. The default value
+/// is used when the variable is either undefined or empty, and it may contain references itself.
+/// `$$` stands for a literal `$`; a `$` not followed by `{` or `$` is kept as is.
+fn expand_vars(s: &str, vars: &Vars) -> ::std::result::Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+
+    let mut rest = s;
+    while let Some(idx) = rest.find('$') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        if rest.starts_with('$') {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+
+        if !rest.starts_with('{') {
+            result.push('$');
+            continue;
+        }
+
+        // Find the matching closing brace, taking nested references in defaults into account
+        let mut depth = 0;
+        let mut end = None;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(i);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let end = match end {
+            Some(end) => end,
+            None => return Err(format!("unterminated variable reference ${}", rest)),
+        };
+
+        let reference = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let (name, default) = match reference.find(":-") {
+            Some(idx) => (&reference[..idx], Some(&reference[idx + 2..])),
+            None => (reference, None),
+        };
+
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable reference ${{{}}}", reference));
+        }
+
+        let value = vars.get(name).cloned()
+            .or_else(|| env::var(name).ok())
+            .and_then(|v| if v.is_empty() && default.is_some() { None } else { Some(v) });
+
+        match (value, default) {
+            (Some(value), _) => result.push_str(&value),
+            (None, Some(default)) => result.push_str(&expand_vars(default, vars)?),
+            (None, None) => return Err(format!("variable {} is not defined", name)),
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Reads the group; `from_inventory` is set for groups in an inventory output, which is data and
+/// must not run further inventory commands.
+fn read_config_group(vars: &Vars, path: String, mut table: Table, from_inventory: bool) -> Result<ConfigGroup> {
+    let inventory = match table.remove("_inventory") {
+        Some(_) if from_inventory =>
+            return Err(format!("{} is defined by an inventory, so it cannot have its own _inventory", group_name(&path)).into()),
+        Some(inventory) => Some(read_inventory(vars, &path, inventory)?),
+        None => None,
+    };
+
     let mut definitions = BTreeMap::new();
 
     fn mkpath(first: &str, second: &str) -> String {
@@ -99,11 +524,24 @@ fn read_config_group(path: String, table: Table) -> Result<ConfigGroup> {
     }
 
     for (k, v) in table {
+        match v {
+            Value::Table(_) | Value::Array(_) => check_name(&path, &k)?,
+            _ => {}
+        }
+
         let item = match v {
-            Value::Table(table) =>
-                ConfigItem::Subgroup(read_config_group(mkpath(&path, &k), table)?),
-            Value::Array(array) =>
-                ConfigItem::Definition(read_config_definition(mkpath(&path, &k), array)?),
+            Value::Table(table) => if let Some(&Value::Array(_)) = table.get("chain") {
+                let definition = read_config_definition_table(vars, mkpath(&path, &k), table)?;
+                check_config_definition(&mkpath(&path, &k), &definition)?;
+                ConfigItem::Definition(definition)
+            } else {
+                ConfigItem::Subgroup(read_config_group(vars, mkpath(&path, &k), table, from_inventory)?)
+            },
+            Value::Array(array) => {
+                let definition = read_config_definition(vars, mkpath(&path, &k), array)?;
+                check_config_definition(&mkpath(&path, &k), &definition)?;
+                ConfigItem::Definition(definition)
+            }
             other =>
                 return Err(format!(
                     "unexpected config item {} in {}, expected table or array, got {}",
@@ -113,15 +551,77 @@ fn read_config_group(path: String, table: Table) -> Result<ConfigGroup> {
         definitions.insert(k, item);
     }
 
+    if let Some(inventory) = inventory {
+        for (k, item) in inventory.definitions {
+            if definitions.contains_key(&k) {
+                return Err(format!(
+                    "inventory of {} defines {}, which is already defined in the config", group_name(&path), k
+                ).into());
+            }
+            definitions.insert(k, item);
+        }
+    }
+
     Ok(ConfigGroup { definitions, })
 }
 
-fn read_config_definition(path: String, array: Array) -> Result<ConfigDefinition> {
+/// Checks that the name can be given to a profile or a group in the group with the path.
+///
+/// `chain` and `_inventory` have a meaning in every group, and `vars` in the root group, so
+/// items with these names would be read as something else.
+pub(crate) fn check_name(path: &str, name: &str) -> Result<()> {
+    if name == "chain" || name == "_inventory" || (path.is_empty() && name == "vars") {
+        return Err(format!(
+            "{} is reserved and cannot be the name of a profile or group in {}", name, group_name(path)
+        ).into());
+    }
+    Ok(())
+}
+
+fn group_name(path: &str) -> &str {
+    if path.is_empty() { "the root group" } else { path }
+}
+
+/// Runs the inventory command configured by `_inventory` and reads its output as the contents of the group.
+fn read_inventory(vars: &Vars, path: &str, value: Value) -> Result<ConfigGroup> {
+    let invalid = |msg: String| format!("inventory of {} is invalid: {}", group_name(path), msg);
+
+    let mut table = match value {
+        Value::Table(table) => table,
+        other => return Err(invalid(format!("expected table, got {}", other.type_str())).into()),
+    };
+
+    let command = match table.remove("command") {
+        Some(Value::String(command)) =>
+            expand_vars(&command, vars).map_err(|e| invalid(format!("command is invalid: {}", e)))?,
+        None => return Err(invalid("command is missing".into()).into()),
+        Some(other) => return Err(invalid(format!("command is invalid: expected string, got {}", other.type_str())).into()),
+    };
+
+    let ttl = match table.remove("ttl") {
+        Some(Value::String(ttl)) =>
+            inventory::parse_ttl(&ttl).map_err(|e| invalid(format!("ttl is invalid: {}", e)))?,
+        Some(Value::Integer(ttl)) if ttl >= 0 => Duration::from_secs(ttl as u64),
+        None => Duration::from_secs(0),
+        Some(other) =>
+            return Err(invalid(format!("ttl is invalid: expected string or non-negative integer, got {}", other.type_str())).into()),
+    };
+
+    match inventory::fetch(&command, ttl) {
+        Ok(Value::Table(table)) => read_config_group(vars, path.into(), table, true),
+        Ok(other) => Err(format!(
+            "inventory of {} failed: expected a JSON object, got {}", group_name(path), other.type_str()
+        ).into()),
+        Err(e) => Err(format!("inventory of {} failed: {}", group_name(path), e).into()),
+    }
+}
+
+fn read_config_definition(vars: &Vars, path: String, array: Array) -> Result<ConfigDefinition> {
     let mut chain = Vec::new();
     for (idx, item) in array.into_iter().enumerate() {
         let item = match item {
-            Value::Table(table) => SingleJumpContext::new(&path, idx).read_from_table(table)?,
-            Value::String(string) => SingleJumpContext::new(&path, idx).read_from_string(string)?,
+            Value::Table(table) => SingleJumpContext::new(vars, &path, idx).read_from_table(table)?,
+            Value::String(string) => SingleJumpContext::new(vars, &path, idx).read_from_string(string)?,
             other => return Err(format!(
                 "unexpected jump configuration in {}, expected table or string, got {}",
                 path, other.type_str()
@@ -129,21 +629,304 @@ fn read_config_definition(path: String, array: Array) -> Result<ConfigDefinition
         };
         chain.push(item);
     }
-    Ok(ConfigDefinition { chain, })
+    Ok(ConfigDefinition { chain, ..ConfigDefinition::default() })
+}
+
+fn read_config_definition_table(vars: &Vars, path: String, mut table: Table) -> Result<ConfigDefinition> {
+    let mut definition = match table.remove("chain") {
+        Some(Value::Array(array)) => read_config_definition(vars, path.clone(), array)?,
+        _ => unreachable!(),  // checked by the caller
+    };
+
+    match table.remove("params") {
+        Some(Value::Table(params)) => for (name, param) in params {
+            let param = match read_param(param) {
+                Ok(param) => param,
+                Err(e) => return Err(format!("parameter {} of {} is invalid: {}", name, path, e).into()),
+            };
+            if let Some(ref default) = param.default {
+                if !param.choices.is_empty() && !param.choices.contains(default) {
+                    return Err(format!(
+                        "parameter {} of {} is invalid: default value {} is not one of the choices",
+                        name, path, default
+                    ).into());
+                }
+            }
+            definition.params.insert(name, param);
+        },
+        None => {}
+        Some(other) =>
+            return Err(format!("params of {} are invalid: expected table, got {}", path, other.type_str()).into()),
+    }
+
+    match table.remove("multiplex") {
+        Some(Value::Boolean(m)) => definition.multiplex = m,
+        None => {}
+        Some(other) =>
+            return Err(format!("multiplex of {} is invalid: expected boolean, got {}", path, other.type_str()).into()),
+    }
+
+    match table.remove("confirm") {
+        Some(Value::Boolean(c)) => definition.confirm = Some(c),
+        None => {}
+        Some(other) =>
+            return Err(format!("confirm of {} is invalid: expected boolean, got {}", path, other.type_str()).into()),
+    }
+
+    match table.remove("record") {
+        Some(Value::Boolean(r)) => definition.record = r,
+        None => {}
+        Some(other) =>
+            return Err(format!("record of {} is invalid: expected boolean, got {}", path, other.type_str()).into()),
+    }
+
+    fn remove_string(table: &mut Table, path: &str, field: &str) -> Result<Option<String>> {
+        match table.remove(field) {
+            Some(Value::String(s)) => Ok(Some(s)),
+            None => Ok(None),
+            Some(other) =>
+                Err(format!("{} of {} is invalid: expected string, got {}", field, path, other.type_str()).into()),
+        }
+    }
+
+    definition.description = remove_string(&mut table, &path, "description")?;
+    definition.env = remove_string(&mut table, &path, "env")?;
+    definition.record_dir = remove_string(&mut table, &path, "record_dir")?;
+
+    match table.remove("tags") {
+        Some(Value::Array(tags)) => for tag in tags {
+            match tag {
+                Value::String(tag) => definition.tags.push(tag),
+                other =>
+                    return Err(format!("tags of {} are invalid: expected string, got {}", path, other.type_str()).into()),
+            }
+        },
+        None => {}
+        Some(other) =>
+            return Err(format!("tags of {} are invalid: expected array, got {}", path, other.type_str()).into()),
+    }
+
+    // The profile-wide transport is used for the last jump unless it is set there explicitly
+    match table.remove("transport") {
+        Some(Value::String(t)) => {
+            let transport = parse_transport(&t)
+                .map_err(|e| format!("transport of {} is invalid: {}", path, e))?;
+            if let Some(last) = definition.chain.last_mut() {
+                if last.transport.is_none() {
+                    last.transport = Some(transport);
+                }
+            }
+        }
+        None => {}
+        Some(other) =>
+            return Err(format!("transport of {} is invalid: expected string, got {}", path, other.type_str()).into()),
+    }
+
+    // A table or array next to the chain was most likely meant as a profile or group, which would
+    // otherwise be dropped silently because the chain turns the whole table into a profile
+    if let Some((k, v)) = table.into_iter().next() {
+        return Err(match v {
+            Value::Table(_) | Value::Array(_) => format!(
+                "{} has a chain, so it is a profile and cannot contain the profile or group {}", path, k
+            ),
+            _ => format!("unknown field {} in {}", k, path),
+        }.into());
+    }
+
+    for (idx, jump) in definition.chain.iter().enumerate() {
+        let mut strings = vec![("host", &jump.host)];
+        strings.extend(jump.user.as_ref().map(|u| ("user", u)));
+        strings.extend(jump.key.as_ref().map(|k| ("key", k)));
+        strings.extend(jump.set_env.values().map(|v| ("set_env", v)));
+        if let State::Enabled(ref tunnel) = jump.tunnel {
+            strings.extend(tunnel.local_host.as_ref().map(|h| ("tunnel", h)));
+            strings.extend(tunnel.remote_host.as_ref().map(|h| ("tunnel", h)));
+        }
+
+        for (field, s) in strings {
+            if let Some(name) = param_references(s).into_iter().find(|n| !definition.params.contains_key(*n)) {
+                return SingleJumpContext::new(vars, &path, idx)
+                    .err(format!("{} refers to undeclared parameter {}", field, name));
+            }
+        }
+    }
+
+    Ok(definition)
+}
+
+fn read_param(value: Value) -> ::std::result::Result<Param, String> {
+    let mut table = match value {
+        Value::Table(table) => table,
+        other => return Err(format!("expected table, got {}", other.type_str())),
+    };
+
+    let default = match table.remove("default") {
+        Some(Value::String(s)) => Some(s),
+        None => None,
+        Some(other) => return Err(format!("default is invalid: expected string, got {}", other.type_str())),
+    };
+
+    let mut choices = Vec::new();
+    match table.remove("choices") {
+        Some(Value::Array(array)) => for choice in array {
+            match choice {
+                Value::String(s) => choices.push(s),
+                other => return Err(format!("choices are invalid: expected string, got {}", other.type_str())),
+            }
+        },
+        None => {}
+        Some(other) => return Err(format!("choices are invalid: expected array, got {}", other.type_str())),
+    }
+
+    Ok(Param { default, choices })
+}
+
+fn parse_key_location(s: &str) -> ::std::result::Result<KeyLocation, String> {
+    match s {
+        "local" => Ok(KeyLocation::Local),
+        "remote" => Ok(KeyLocation::Remote),
+        other => Err(format!("expected local or remote, got {}", other)),
+    }
+}
+
+fn parse_transport(s: &str) -> ::std::result::Result<Transport, String> {
+    match s {
+        "ssh" => Ok(Transport::Ssh),
+        "mosh" => Ok(Transport::Mosh),
+        "autossh" => Ok(Transport::Autossh),
+        other => Err(format!("expected ssh, mosh or autossh, got {}", other)),
+    }
+}
+
+/// Refuses combinations of options which cannot be rendered into a command.
+fn check_config_definition(path: &str, definition: &ConfigDefinition) -> Result<()> {
+    let err = |idx: usize, msg: &str| Err(jump_error(path, idx, msg).into());
+
+    if definition.chain.is_empty() {
+        return Err(format!("{} has no jumps", path).into());
+    }
+
+    if let Some(&Some(Transport::Mosh)) = definition.chain.first().map(|j| &j.transport) {
+        if definition.multiplex {
+            return err(0, "multiplex is not supported by mosh transport");
+        }
+    }
+
+    // Jumps before the last one with a local key are connected through ProxyCommand
+    let proxied = proxied_jumps(&definition.chain);
+
+    let mut tunnel_propagated = false;
+    for (idx, jump) in definition.chain.iter().enumerate() {
+        if idx == 0 && jump.key_location == Some(KeyLocation::Remote) {
+            return err(idx, "key_location cannot be remote for the first jump, which is connected from the local machine");
+        }
+
+        if idx < proxied {
+            let msg = |what: &str| format!(
+                "{}, since jump {} has a local key and all jumps before it are connected from the local machine",
+                what, proxied + 1
+            );
+            if jump.key_location == Some(KeyLocation::Remote) {
+                return err(idx, &msg("key_location cannot be remote"));
+            }
+            match jump.tunnel {
+                State::Enabled(_) => return err(idx, &msg("tunnels are not supported")),
+                State::Unset if tunnel_propagated => return err(idx, &msg("tunnels are not supported")),
+                _ => {}
+            }
+            if jump.transport == Some(Transport::Autossh) {
+                return err(idx, &msg("autossh transport is not supported"));
+            }
+            // ProxyCommand only forwards the connection, so there is no session to set variables in
+            if !jump.set_env.is_empty() {
+                return err(idx, &msg("set_env is not supported"));
+            }
+            if !jump.send_env.is_empty() {
+                return err(idx, &msg("send_env is not supported"));
+            }
+        }
+
+        match jump.transport {
+            Some(Transport::Mosh) => {
+                if idx != definition.chain.len() - 1 {
+                    return err(idx, "mosh transport is only supported for the last jump");
+                }
+                match jump.tunnel {
+                    State::Enabled(_) => return err(idx, "mosh transport does not support tunnels"),
+                    State::Unset if tunnel_propagated => return err(
+                        idx, "mosh transport does not support tunnels, but a tunnel of a previous jump \
+                              is propagated to this jump; disable it with tunnel = false"
+                    ),
+                    _ => {}
+                }
+                if let State::Enabled(_) = jump.no_command {
+                    return err(idx, "mosh transport does not support no_command");
+                }
+                if let State::Enabled(_) = jump.agent_passthrough {
+                    return err(idx, "mosh transport does not support agent_passthrough");
+                }
+                if jump.verbose {
+                    return err(idx, "mosh transport does not support verbose");
+                }
+                if idx > 0 && idx == proxied {
+                    return err(idx, "mosh transport does not support local keys on jumps after the first one");
+                }
+                if !jump.set_env.is_empty() {
+                    return err(idx, "mosh transport does not support set_env");
+                }
+                if !jump.send_env.is_empty() {
+                    return err(idx, "mosh transport does not support send_env");
+                }
+            }
+            Some(Transport::Autossh) => if jump.ssh_path.is_some() {
+                return err(idx, "ssh_path is not supported by autossh transport");
+            },
+            Some(Transport::Ssh) | None => {}
+        }
+
+        match jump.tunnel {
+            State::Enabled(_) => tunnel_propagated = true,
+            State::Disabled => tunnel_propagated = false,
+            State::Unset => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the name can be used in `SetEnv`, i.e. matches `[A-Za-z_][A-Za-z0-9_]*`.
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+fn jump_error(path: &str, idx: usize, msg: &str) -> String {
+    format!("jump {} of {}: {}", idx + 1, path, msg)
 }
 
 struct SingleJumpContext<'a> {
+    vars: &'a Vars,
     path: &'a str,
     idx: usize,
 }
 
 impl<'a> SingleJumpContext<'a> {
-    fn new(path: &'a str, idx: usize) -> SingleJumpContext<'a> {
-        SingleJumpContext { path: path.into(), idx, }
+    fn new(vars: &'a Vars, path: &'a str, idx: usize) -> SingleJumpContext<'a> {
+        SingleJumpContext { vars, path: path.into(), idx, }
     }
 
     fn err<T, S: AsRef<str>>(&self, msg: S) -> Result<T> {
-        Err(format!("jump {} of {}: {}", self.idx + 1, self.path, msg.as_ref()).into())
+        Err(jump_error(self.path, self.idx, msg.as_ref()).into())
+    }
+
+    fn expand(&self, field: &str, s: String) -> Result<String> {
+        match expand_vars(&s, self.vars) {
+            Ok(s) => Ok(s),
+            Err(e) => self.err(format!("{} is invalid: {}", field, e)),
+        }
     }
 
     fn read_from_string(&self, s: String) -> Result<SingleJump> {
@@ -153,7 +936,7 @@ impl<'a> SingleJumpContext<'a> {
 
     fn read_from_table(&self, mut table: Table) -> Result<SingleJump> {
         let host = match table.remove("host") {
-            Some(Value::String(host)) => host,
+            Some(Value::String(host)) => self.expand("host", host)?,
             Some(other) => return self.err(format!("host is invalid: expected string, got {}", other.type_str())),
             None => return self.err("host is missing"),
         };
@@ -172,22 +955,31 @@ impl<'a> SingleJumpContext<'a> {
         };
 
         let user = match table.remove("user") {
-            Some(Value::String(u)) => Some(u),
+            Some(Value::String(u)) => Some(self.expand("user", u)?),
             None => user.map(Into::into),
             Some(other) =>
                 return self.err(format!("user is invalid: expected string, got {}", other.type_str()))
         };
 
         let key = match table.remove("key") {
-            Some(Value::String(k)) => Some(k),
+            Some(Value::String(k)) => Some(self.expand("key", k)?),
             None => key.map(Into::into),
             Some(other) =>
                 return self.err(format!("key is invalid: expected string, got {}", other.type_str()))
         };
 
+        let key_location = match table.remove("key_location") {
+            Some(Value::String(l)) => match parse_key_location(&l) {
+                Ok(location) => Some(location),
+                Err(e) => return self.err(format!("key_location is invalid: {}", e)),
+            },
+            None => None,
+            Some(other) => return self.err(format!("key_location is invalid: expected string, got {}", other.type_str())),
+        };
+
         let tunnel = match table.remove("tunnel") {
             Some(Value::Table(table)) => State::Enabled(self.tunnel_from_table(table)?),
-            Some(Value::String(string)) => State::Enabled(self.tunnel_from_string(string)?),
+            Some(Value::String(string)) => State::Enabled(self.tunnel_from_string(self.expand("tunnel", string)?)?),
             Some(Value::Integer(integer)) => State::Enabled(self.tunnel_from_integer(integer)?),
             Some(Value::Boolean(false)) => State::Disabled,
             None => State::Unset,
@@ -213,7 +1005,54 @@ impl<'a> SingleJumpContext<'a> {
             Some(other) => return self.err(format!("no_command is invalid: expected boolean, got {}", other.type_str())),
         };
 
-        Ok(SingleJump { host, port, user, key, tunnel, verbose, agent_passthrough, no_command, })
+        let transport = match table.remove("transport") {
+            Some(Value::String(t)) => match parse_transport(&t) {
+                Ok(transport) => Some(transport),
+                Err(e) => return self.err(format!("transport is invalid: {}", e)),
+            },
+            None => None,
+            Some(other) => return self.err(format!("transport is invalid: expected string, got {}", other.type_str())),
+        };
+
+        let ssh_path = match table.remove("ssh_path") {
+            Some(Value::String(p)) => Some(self.expand("ssh_path", p)?),
+            None => None,
+            Some(other) => return self.err(format!("ssh_path is invalid: expected string, got {}", other.type_str())),
+        };
+
+        let mut set_env = BTreeMap::new();
+        match table.remove("set_env") {
+            Some(Value::Table(vars)) => for (name, value) in vars {
+                if !is_var_name(&name) {
+                    return self.err(format!("set_env is invalid: {:?} is not a valid variable name", name));
+                }
+                match value {
+                    Value::String(v) => { set_env.insert(name, self.expand("set_env", v)?); }
+                    other => return self.err(format!("set_env is invalid: expected string for {}, got {}", name, other.type_str())),
+                }
+            },
+            None => {}
+            Some(other) => return self.err(format!("set_env is invalid: expected table, got {}", other.type_str())),
+        }
+
+        let mut send_env = Vec::new();
+        match table.remove("send_env") {
+            Some(Value::Array(patterns)) => for pattern in patterns {
+                match pattern {
+                    Value::String(ref p) if p.is_empty() || p.contains(char::is_whitespace) =>
+                        return self.err(format!("send_env is invalid: {:?} is not a valid variable pattern", p)),
+                    Value::String(p) => send_env.push(p),
+                    other => return self.err(format!("send_env is invalid: expected string, got {}", other.type_str())),
+                }
+            },
+            None => {}
+            Some(other) => return self.err(format!("send_env is invalid: expected array, got {}", other.type_str())),
+        }
+
+        Ok(SingleJump {
+            host, port, user, key, key_location, tunnel, verbose, agent_passthrough, no_command, transport, ssh_path,
+            set_env, send_env,
+        })
     }
 
     fn tunnel_from_string(&self, s: String) -> Result<Tunnel> {
@@ -278,11 +1117,28 @@ impl<'a> SingleJumpContext<'a> {
         }
     }
 
-    fn tunnel_from_table(&self, t: Table) -> Result<Tunnel> {
-        match Value::Table(t).try_into() {
-            Ok(t) => Ok(t),
-            Err(e) => self.err(format!("tunnel is invalid: {}", e)),
-        }
+    fn tunnel_from_table(&self, mut t: Table) -> Result<Tunnel> {
+        let port = |t: &mut Table, field: &str| match t.remove(field) {
+            Some(Value::Integer(i)) if i >= u16::min_value() as i64 && i <= u16::max_value() as i64 => Ok(Some(i as u16)),
+            None => Ok(None),
+            Some(Value::Integer(i)) =>
+                self.err(format!("tunnel is invalid: {} is invalid: expected number from 0 to 65536, got {}", field, i)),
+            Some(other) =>
+                self.err(format!("tunnel is invalid: {} is invalid: expected number from 0 to 65536, got {}", field, other.type_str())),
+        };
+        let host = |t: &mut Table, field: &str| match t.remove(field) {
+            Some(Value::String(host)) => Ok(Some(self.expand("tunnel", host)?)),
+            None => Ok(None),
+            Some(other) =>
+                self.err(format!("tunnel is invalid: {} is invalid: expected string, got {}", field, other.type_str())),
+        };
+
+        Ok(Tunnel {
+            local_port: port(&mut t, "local_port")?,
+            local_host: host(&mut t, "local_host")?,
+            remote_port: port(&mut t, "remote_port")?,
+            remote_host: host(&mut t, "remote_host")?,
+        })
     }
 
     fn parse_host<'h>(&self, host: &'h str) -> Result<HostInfo<'h>> {
@@ -393,11 +1249,21 @@ whatever3 = [
     "#;
 
     use super::*;
+    use testing::TempDir;
 
     #[test]
     fn test_parsing() {
         let config = load_from_string(TEST_DATA).unwrap();
 
+        assert_eq!(config.root.get_definition("my.a.b.googolplex").unwrap().chain[0].host, "some.server");
+        assert!(config.root.get_path(&["my", "a"]).unwrap().is_group());
+        assert_eq!(config.root.find(|_, d| d.chain.len() == 5).unwrap().0, "my.whatever4");
+
+        let error = |path| config.root.get_definition(path).unwrap_err().to_string();
+        assert_eq!(error("my.a"), "my.a is a group, not a profile");
+        assert_eq!(error("be_3.x.y"), "be_3 is a profile, not a group, so it has no x");
+        assert_eq!(error("my.nothing.x"), "my.nothing does not exist");
+
         let mut root = config.root.definitions;
 
         // be_3 = ["user@be-3.example.com:2244:~/.ssh/be.pem"]
@@ -407,15 +1273,21 @@ whatever3 = [
                 chain: vec![
                     SingleJump {
                         host: "be-3.example.com".into(),
-                        port: 2244,
+                        port: Some(2244),
                         user: Some("user".into()),
                         key: Some("~/.ssh/be.pem".into()),
-                        tunnel: None,
+                        key_location: None,
+                        tunnel: State::Unset,
                         verbose: false,
-                        agent_passthrough: false,
-                        no_command: false,
+                        agent_passthrough: State::Unset,
+                        no_command: State::Unset,
+                        transport: None,
+                        ssh_path: None,
+                        set_env: BTreeMap::new(),
+                        send_env: Vec::new(),
                     }
-                ]
+                ],
+                ..ConfigDefinition::default()
             })
         );
 
@@ -426,15 +1298,21 @@ whatever3 = [
                 chain: vec![
                     SingleJump {
                         host: "be-3.example.com".into(),
-                        port: 1234,
+                        port: Some(1234),
                         user: None,
                         key: Some("/bla/bla.pem".into()),
-                        tunnel: None,
+                        key_location: None,
+                        tunnel: State::Unset,
                         verbose: false,
-                        agent_passthrough: false,
-                        no_command: false,
+                        agent_passthrough: State::Unset,
+                        no_command: State::Unset,
+                        transport: None,
+                        ssh_path: None,
+                        set_env: BTreeMap::new(),
+                        send_env: Vec::new(),
                     }
-                ]
+                ],
+                ..ConfigDefinition::default()
             })
         );
 
@@ -453,37 +1331,410 @@ whatever3 = [
                 chain: vec![
                     SingleJump {
                         host: "serverplex".into(),
-                        port: 22,
+                        port: None,
                         user: None,
                         key: None,
-                        tunnel: Some(Tunnel {
+                        key_location: None,
+                        tunnel: State::Enabled(Tunnel {
                             local_host: None,
                             local_port: Some(1221),
                             remote_host: None,
                             remote_port: Some(4443),
                         }),
                         verbose: false,
-                        agent_passthrough: false,
-                        no_command: false,
+                        agent_passthrough: State::Unset,
+                        no_command: State::Unset,
+                        transport: None,
+                        ssh_path: None,
+                        set_env: BTreeMap::new(),
+                        send_env: Vec::new(),
                     },
                     SingleJump {
                         host: "transplex".into(),
-                        port: 22,
+                        port: None,
                         user: None,
                         key: None,
-                        tunnel: Some(Tunnel {
+                        key_location: None,
+                        tunnel: State::Enabled(Tunnel {
                             local_host: None,
                             local_port: Some(4443),
                             remote_host: None,
                             remote_port: Some(443),
                         }),
                         verbose: false,
-                        agent_passthrough: false,
-                        no_command: false,
+                        agent_passthrough: State::Unset,
+                        no_command: State::Unset,
+                        transport: None,
+                        ssh_path: None,
+                        set_env: BTreeMap::new(),
+                        send_env: Vec::new(),
                     },
-                ]
+                ],
+                ..ConfigDefinition::default()
             })
         );
 
     }
+
+    #[test]
+    fn test_vars() {
+        let config = load_from_string(r#"
+[vars]
+domain = "example.com"
+key_dir = "${SSHC_TEST_UNDEFINED_KEY_DIR:-/keys}"
+
+[a]
+b = ["deploy@app.${domain}::${key_dir}/${SSHC_TEST_UNDEFINED_ENV:-app}.pem"]
+c = [{ host = "$${domain}", tunnel = "${SSHC_TEST_UNDEFINED_ENV:-}:1234|db.${domain}:" }]
+        "#).unwrap();
+
+        let mut a = match config.root.definitions.get("a").unwrap() {
+            &ConfigItem::Subgroup(ref a) => a.definitions.clone(),
+            other => panic!("Invalid subgroup a: {:?}", other),
+        };
+
+        let b = match a.remove("b").unwrap() {
+            ConfigItem::Definition(d) => d.chain.into_iter().next().unwrap(),
+            other => panic!("Invalid definition a.b: {:?}", other),
+        };
+        assert_eq!(b.host, "app.example.com");
+        assert_eq!(b.user, Some("deploy".into()));
+        assert_eq!(b.key, Some("/keys/app.pem".into()));
+
+        let c = match a.remove("c").unwrap() {
+            ConfigItem::Definition(d) => d.chain.into_iter().next().unwrap(),
+            other => panic!("Invalid definition a.c: {:?}", other),
+        };
+        assert_eq!(c.host, "${domain}");
+        assert_eq!(c.tunnel, State::Enabled(Tunnel {
+            local_host: None,
+            local_port: Some(1234),
+            remote_host: Some("db.example.com".into()),
+            remote_port: None,
+        }));
+
+        let err = load_from_string(r#"a = ["${SSHC_TEST_UNDEFINED_ENV}.example.com"]"#).unwrap_err();
+        assert_eq!(err.to_string(), "jump 1 of a: host is invalid: variable SSHC_TEST_UNDEFINED_ENV is not defined");
+    }
+
+    #[test]
+    fn test_params() {
+        let config = load_from_string(r#"
+[app]
+params = { n = { default = "1", choices = ["1", "2", "3"] }, region = {} }
+chain = [
+    { host = "bastion.{region}.example.com" },
+    { host = "app-{n}.{region}.internal", tunnel = "{ignored}:8080|:" },
+]
+        "#);
+        let err = config.unwrap_err();
+        assert_eq!(err.to_string(), "jump 2 of app: tunnel refers to undeclared parameter ignored");
+
+        let config = load_from_string(r#"
+[app]
+params = { n = { default = "1", choices = ["1", "2", "3"] }, region = {} }
+chain = ["bastion.{region}.example.com", "app-{n}.{region}.internal", "$${n}.internal"]
+        "#).unwrap();
+
+        let app = match config.root.definitions.get("app").unwrap() {
+            &ConfigItem::Definition(ref app) => app.clone(),
+            other => panic!("Invalid definition app: {:?}", other),
+        };
+        assert_eq!(app.params.len(), 2);
+
+        let mut values = BTreeMap::new();
+        assert_eq!(app.instantiate(&values).unwrap_err().to_string(), "parameter region is not set");
+
+        values.insert("region".to_owned(), "eu".to_owned());
+        let hosts: Vec<_> = app.instantiate(&values).unwrap().chain.into_iter().map(|j| j.host).collect();
+        assert_eq!(hosts, vec!["bastion.eu.example.com", "app-1.eu.internal", "${n}.internal"]);
+
+        values.insert("n".to_owned(), "4".to_owned());
+        assert_eq!(
+            app.instantiate(&values).unwrap_err().to_string(),
+            "invalid value 4 for parameter n, expected one of: 1, 2, 3"
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let config = load_from_string(r#"
+[prod.app]
+description = "Main application server"
+tags = ["web", "critical"]
+env = "prod"
+chain = ["app.example.com"]
+        "#).unwrap();
+
+        let app = &config.root.iter_definitions()[0].1;
+        assert_eq!(app.description, Some("Main application server".into()));
+        assert_eq!(app.tags, vec!["web".to_owned(), "critical".into()]);
+        assert_eq!(app.env, Some("prod".into()));
+        assert!(app.requires_confirmation());
+
+        let config = load_from_string(r#"
+a = { env = "prod", confirm = false, chain = ["a"] }
+b = { confirm = true, chain = ["b"] }
+c = { env = "staging", chain = ["c"] }
+        "#).unwrap();
+        let confirmations: Vec<_> = config.root.iter_definitions().into_iter()
+            .map(|(_, d)| d.requires_confirmation())
+            .collect();
+        assert_eq!(confirmations, vec![false, true, false]);
+
+        let err = load_from_string(r#"
+app = { tags = "web", chain = ["app.example.com"] }
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "tags of app are invalid: expected array, got string");
+    }
+
+    #[test]
+    fn test_transport_checks() {
+        let config = load_from_string(r#"
+a = { transport = "mosh", chain = ["bastion", "app"] }
+        "#).unwrap();
+        match config.root.definitions.get("a").unwrap() {
+            &ConfigItem::Definition(ref a) =>
+                assert_eq!(a.chain.iter().map(|j| j.transport).collect::<Vec<_>>(), vec![None, Some(Transport::Mosh)]),
+            other => panic!("Invalid definition a: {:?}", other),
+        }
+
+        let err = load_from_string(r#"
+a = [{ host = "bastion", transport = "mosh" }, { host = "app" }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "jump 1 of a: mosh transport is only supported for the last jump");
+
+        let err = load_from_string(r#"
+a = [{ host = "bastion", tunnel = 8080 }, { host = "app", transport = "mosh" }]
+        "#).unwrap_err();
+        assert!(err.to_string().starts_with("jump 2 of a: mosh transport does not support tunnels"));
+
+        load_from_string(r#"
+a = [{ host = "bastion", tunnel = 8080 }, { host = "app", transport = "mosh", tunnel = false }]
+        "#).unwrap();
+
+        let err = load_from_string(r#"
+a = [{ host = "app", transport = "mosh", verbose = true }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "jump 1 of a: mosh transport does not support verbose");
+    }
+
+    #[test]
+    fn test_empty_chain() {
+        assert_eq!(load_from_string("a = []").unwrap_err().to_string(), "a has no jumps");
+        assert_eq!(load_from_string("[a]\nb = { chain = [] }").unwrap_err().to_string(), "a.b has no jumps");
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        let err = load_from_string("[work.chain]\napp = [\"app\"]").unwrap_err();
+        assert_eq!(err.to_string(), "chain is reserved and cannot be the name of a profile or group in work");
+
+        let err = load_from_string("chain = [\"app\"]").unwrap_err();
+        assert_eq!(err.to_string(), "chain is reserved and cannot be the name of a profile or group in the root group");
+    }
+
+    #[test]
+    fn test_unknown_profile_fields() {
+        let err = load_from_string("[work]\nchain = [\"h\"]\nother = [\"o\"]").unwrap_err();
+        assert_eq!(err.to_string(), "work has a chain, so it is a profile and cannot contain the profile or group other");
+
+        let err = load_from_string("[work]\nchain = [\"h\"]\n[work.app]\nchain = [\"app\"]").unwrap_err();
+        assert_eq!(err.to_string(), "work has a chain, so it is a profile and cannot contain the profile or group app");
+
+        let err = load_from_string("work = { chain = [\"h\"], descripton = \"typo\" }").unwrap_err();
+        assert_eq!(err.to_string(), "unknown field descripton in work");
+    }
+
+    #[test]
+    fn test_env() {
+        let config = load_from_string(r#"
+[app]
+params = { cluster = { default = "eu" } }
+chain = [{ host = "app", set_env = { KUBE_CONTEXT = "prod-{cluster}" }, send_env = ["LC_*"] }]
+        "#).unwrap();
+        let app = config.root.get_definition("app").unwrap().instantiate(&BTreeMap::new()).unwrap();
+        assert_eq!(app.chain[0].set_env.get("KUBE_CONTEXT").map(String::as_str), Some("prod-eu"));
+        assert_eq!(app.chain[0].send_env, vec!["LC_*".to_owned()]);
+
+        for name in &["1A", "A.B", "A-B", "é"] {
+            let err = load_from_string(&format!(r#"app = [{{ host = "app", set_env = {{ "{}" = "1" }} }}]"#, name)).unwrap_err();
+            assert!(err.to_string().ends_with("is not a valid variable name"), "{}", err);
+        }
+        let err = load_from_string(r#"
+app = [{ host = "app", set_env = { "A B" = "1" } }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), r#"jump 1 of app: set_env is invalid: "A B" is not a valid variable name"#);
+
+        let err = load_from_string(r#"
+app = [{ host = "app", send_env = "LC_*" }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "jump 1 of app: send_env is invalid: expected array, got string");
+
+        let err = load_from_string(r#"
+app = [{ host = "app", transport = "mosh", set_env = { A = "1" } }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "jump 1 of app: mosh transport does not support set_env");
+
+        let err = load_from_string(r#"
+app = [{ host = "bastion", set_env = { A = "1" } }, { host = "app", key = "~/.ssh/app", key_location = "local" }]
+        "#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "jump 1 of app: set_env is not supported, since jump 2 has a local key and all jumps before it \
+             are connected from the local machine"
+        );
+
+        let err = load_from_string(r#"
+app = [{ host = "bastion", send_env = ["LANG"] }, { host = "app", key = "~/.ssh/app", key_location = "local" }]
+        "#).unwrap_err();
+        assert!(err.to_string().starts_with("jump 1 of app: send_env is not supported, since jump 2 has a local key"));
+    }
+
+    #[test]
+    fn test_key_location_checks() {
+        let config = load_from_string(r#"
+a = [{ host = "bastion" }, { host = "app", key = "~/.ssh/app", key_location = "local" }, { host = "db", key = "/db" }]
+        "#).unwrap();
+        let a = config.root.get_definition("a").unwrap();
+        assert_eq!(a.chain.iter().map(|j| j.key_location).collect::<Vec<_>>(), vec![None, Some(KeyLocation::Local), None]);
+
+        let err = load_from_string(r#"
+a = [{ host = "bastion", key = "~/.ssh/bastion", key_location = "remote" }]
+        "#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "jump 1 of a: key_location cannot be remote for the first jump, which is connected from the local machine"
+        );
+
+        let err = load_from_string(r#"
+a = [{ host = "bastion" }, { host = "gw", key = "/gw", key_location = "remote" }, { host = "app", key = "/app", key_location = "local" }]
+        "#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "jump 2 of a: key_location cannot be remote, since jump 3 has a local key and all jumps before it \
+             are connected from the local machine"
+        );
+
+        let err = load_from_string(r#"
+a = [{ host = "bastion", tunnel = 8080 }, { host = "app", key = "/app", key_location = "local" }]
+        "#).unwrap_err();
+        assert!(err.to_string().starts_with("jump 1 of a: tunnels are not supported, since jump 2 has a local key"));
+
+        let err = load_from_string(r#"
+a = [{ host = "bastion" }, { host = "app", key = "/app", key_location = "sideways" }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "jump 2 of a: key_location is invalid: expected local or remote, got sideways");
+    }
+
+    #[test]
+    fn test_inventory() {
+        let config = load_from_string(r#"
+[cloud]
+db = ["db"]
+_inventory = { command = "echo '{\"web\": [\"web-1\"], \"eu\": { \"app\": { \"chain\": [\"app\"], \"tags\": [\"eu\"] } } }'" }
+        "#).unwrap();
+        let paths: Vec<_> = config.root.iter_definitions().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["cloud.db", "cloud.eu.app", "cloud.web"]);
+        assert_eq!(config.root.get_definition("cloud.eu.app").unwrap().tags, vec!["eu".to_owned()]);
+
+        let err = load_from_string(r#"
+[cloud]
+web = ["web"]
+_inventory = { command = "echo '{\"web\": [\"web-1\"]}'" }
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "inventory of cloud defines web, which is already defined in the config");
+
+        let err = load_from_string(r#"_inventory = { command = "echo unreachable >&2; exit 2" }"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "inventory of the root group failed: echo unreachable >&2; exit 2 failed with exit code 2: unreachable"
+        );
+
+        let err = load_from_string(r#"_inventory = { command = "true", ttl = "soon" }"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "inventory of the root group is invalid: ttl is invalid: expected a number with an optional s, m, h or d suffix, got soon"
+        );
+
+        let err = load_from_string(r#"_inventory = { command = "true", ttl = -5 }"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "inventory of the root group is invalid: ttl is invalid: expected string or non-negative integer, got integer"
+        );
+    }
+
+    #[test]
+    fn test_nested_inventory() {
+        let dir = TempDir::new("nested-inventory");
+        let marker = dir.join("marker");
+
+        let output = dir.write("eu.json", &format!(r#"{{"_inventory": {{"command": "touch {}"}}}}"#, marker.display()), 0o600);
+        let err = load_from_string(&format!("[eu]\n_inventory = {{ command = \"cat {}\" }}", output.display())).unwrap_err();
+        assert_eq!(err.to_string(), "eu is defined by an inventory, so it cannot have its own _inventory");
+
+        let output = dir.write("all.json", &format!(r#"{{"eu": {{"_inventory": {{"command": "touch {}"}}}}}}"#, marker.display()), 0o600);
+        let err = load_from_string(&format!("_inventory = {{ command = \"cat {}\" }}", output.display())).unwrap_err();
+        assert_eq!(err.to_string(), "eu is defined by an inventory, so it cannot have its own _inventory");
+        assert!(!marker.exists());
+
+        let output = dir.write("vars.json", r#"{"vars": {"user": "root"}}"#, 0o600);
+        let err = load_from_string(&format!("_inventory = {{ command = \"cat {}\" }}", output.display())).unwrap_err();
+        assert_eq!(err.to_string(), "vars is reserved and cannot be the name of a profile or group in the root group");
+    }
+
+    #[test]
+    fn test_formats() {
+        let toml = load_from_string(r#"
+db = ["admin@db:2222"]
+
+[work.app]
+tags = ["web"]
+params = { n = { default = "1", choices = ["1", "2"] } }
+chain = [
+    { host = "bastion", tunnel = { local_port = 8080, remote_host = "app{n}" } },
+    { host = "app{n}", agent_passthrough = false },
+]
+        "#).unwrap();
+
+        let yaml = load_from_string_as(r#"
+db: ["admin@db:2222"]
+work:
+  app:
+    tags: [web]
+    params:
+      n: { default: "1", choices: ["1", "2"] }
+    chain:
+      - host: bastion
+        tunnel: { local_port: 8080, remote_host: "app{n}" }
+      - { host: "app{n}", agent_passthrough: false }
+        "#, Format::Yaml).unwrap();
+        assert_eq!(yaml, toml);
+
+        let json = load_from_string_as(r#"{
+            "db": ["admin@db:2222"],
+            "work": {
+                "app": {
+                    "tags": ["web"],
+                    "params": { "n": { "default": "1", "choices": ["1", "2"] } },
+                    "chain": [
+                        { "host": "bastion", "tunnel": { "local_port": 8080, "remote_host": "app{n}" } },
+                        { "host": "app{n}", "agent_passthrough": false }
+                    ]
+                }
+            }
+        }"#, Format::Json).unwrap();
+        assert_eq!(json, toml);
+
+        let errors: Vec<_> = vec![
+            load_from_string(r#"a = [{ host = "a", port = "22" }]"#),
+            load_from_string_as("a: [{ host: a, port: '22' }]", Format::Yaml),
+            load_from_string_as(r#"{ "a": [{ "host": "a", "port": "22" }] }"#, Format::Json),
+        ].into_iter().map(|r| r.unwrap_err().to_string()).collect();
+        assert_eq!(errors, vec!["jump 1 of a: port is invalid: expected number from 0 to 65536, got string"; 3]);
+
+        assert_eq!(Format::from_path(Path::new("config.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("config.json")), Format::Json);
+        assert_eq!(Format::from_path(Path::new("config")), Format::Toml);
+    }
 }
@@ -0,0 +1,49 @@
+//! Directories where sshc keeps its files.
+//!
+//! The module is included both by the library and by the binary, each using only some of the directories.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// Directory for sockets and other files which do not outlive the session.
+///
+/// This is `$XDG_RUNTIME_DIR/sshc`, falling back to a per-user directory in `/tmp`.
+pub fn runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir).join("sshc"),
+        _ => env::temp_dir().join(format!("sshc-{}", env::var("USER").unwrap_or_default())),
+    }
+}
+
+/// Directory for data which should persist between runs, like history.
+///
+/// This is `$XDG_STATE_HOME/sshc`, falling back to `~/.local/state/sshc`.
+pub fn state_dir() -> PathBuf {
+    match env::var_os("XDG_STATE_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir).join("sshc"),
+        _ => home_dir().join(".local/state/sshc"),
+    }
+}
+
+/// Directory for data which can be recreated at any time, like inventory command outputs.
+///
+/// This is `$XDG_CACHE_HOME/sshc`, falling back to `~/.cache/sshc`.
+pub fn cache_dir() -> PathBuf {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir).join("sshc"),
+        _ => home_dir().join(".cache/sshc"),
+    }
+}
+
+pub fn home_dir() -> PathBuf {
+    env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/"))
+}
+
+/// Creates the directory and its parents, making the directory accessible only to the user.
+pub fn ensure_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(path)
+}
@@ -0,0 +1,514 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use toml_edit::{self, Array, ArrayOfTables, Document, InlineTable, Item, Table, TableLike, Value};
+
+use config::{self, Config, ErrorKind, Result};
+
+/// Raw values of the jump fields as written in the configuration file, before variables
+/// and parameters are substituted. Empty strings and `None` mean that the field is not set.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct JumpFields {
+    pub host: String,
+    pub port: String,
+    pub user: String,
+    pub key: String,
+    pub tunnel: String,
+    pub verbose: Option<bool>,
+    pub agent_passthrough: Option<bool>,
+    pub no_command: Option<bool>,
+}
+
+impl JumpFields {
+    /// Returns `true` if the jump can be written as a plain host string.
+    fn is_host_only(&self) -> bool {
+        JumpFields { host: self.host.clone(), ..JumpFields::default() } == *self
+    }
+}
+
+/// Editor of the configuration file which keeps comments and formatting of the parts it does not change.
+pub struct ConfigEditor {
+    path: PathBuf,
+    document: Document,
+}
+
+/// The chain of a profile, either an array of hosts and inline tables, or an array of tables.
+enum Chain<'a> {
+    Array(&'a mut Array),
+    Tables(&'a mut ArrayOfTables),
+}
+
+impl ConfigEditor {
+    pub fn open(path: &Path) -> Result<ConfigEditor> {
+        if config::Format::from_path(path) != config::Format::Toml {
+            return Err("only TOML configs can be edited from the menu".into());
+        }
+
+        let mut f = File::open(path)?;
+
+        let mut data = String::new();
+        f.read_to_string(&mut data)?;
+
+        ConfigEditor::from_string(path, &data)
+    }
+
+    fn from_string(path: &Path, s: &str) -> Result<ConfigEditor> {
+        let document = s.parse::<Document>().map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        Ok(ConfigEditor { path: path.into(), document, })
+    }
+
+    /// Returns the jumps of the profile as they are written in the file.
+    pub fn jumps(&mut self, profile: &str) -> Result<Vec<JumpFields>> {
+        let chain = self.chain(profile)?;
+        Ok(match chain {
+            Chain::Array(array) => array.iter().map(|jump| match *jump {
+                Value::InlineTable(ref table) => read_fields(table),
+                ref other => JumpFields { host: other.as_str().unwrap_or_default().into(), ..JumpFields::default() },
+            }).collect(),
+            Chain::Tables(tables) => tables.iter().map(|table| read_fields(table)).collect(),
+        })
+    }
+
+    /// Replaces the jumps of the profile, changing only the fields which differ from the current ones.
+    pub fn set_jumps(&mut self, profile: &str, jumps: &[JumpFields]) -> Result<()> {
+        let old = self.jumps(profile)?;
+
+        match self.chain(profile)? {
+            Chain::Array(array) => {
+                while array.len() > jumps.len() {
+                    let last = array.len() - 1;
+                    array.remove(last);
+                }
+                for (idx, jump) in jumps.iter().enumerate() {
+                    match old.get(idx) {
+                        Some(old) if old == jump => {}
+                        Some(old) => {
+                            let value = match array.get_mut(idx) {
+                                Some(&mut Value::InlineTable(ref mut table)) => {
+                                    write_fields(table, old, jump)?;
+                                    None
+                                }
+                                _ => Some(jump_value(jump)?),
+                            };
+                            if let Some(value) = value {
+                                array.replace(idx, value);
+                            }
+                        }
+                        None => array.push(jump_value(jump)?),
+                    }
+                }
+            }
+            Chain::Tables(tables) => {
+                while tables.len() > jumps.len() {
+                    let last = tables.len() - 1;
+                    tables.remove(last);
+                }
+                for (idx, jump) in jumps.iter().enumerate() {
+                    if idx < tables.len() {
+                        write_fields(tables.get_mut(idx).unwrap(), &old[idx], jump)?;
+                    } else {
+                        let mut table = Table::new();
+                        write_fields(&mut table, &JumpFields::default(), jump)?;
+                        tables.push(table);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a new profile in the array form, creating its groups if necessary.
+    pub fn add(&mut self, profile: &str, jumps: &[JumpFields]) -> Result<()> {
+        let mut array = Array::new();
+        for jump in jumps {
+            array.push(jump_value(jump)?);
+        }
+        self.insert(profile, toml_edit::value(array))
+    }
+
+    /// Removes the profile or the group.
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        let (parent, name) = split_path(path);
+        let removed = self.group_mut(parent)?.remove(name);
+        match removed {
+            Some(_) => Ok(()),
+            None => Err(format!("{} does not exist", path).into()),
+        }
+    }
+
+    /// Moves the profile or the group to a new path; renaming is moving within the same group.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        if to == from || to.starts_with(&format!("{}.", from)) {
+            return Err(format!("cannot move {} into itself", from).into());
+        }
+
+        // Check the target before taking the item out, so that a failed move does not lose it
+        self.check_new_path(to)?;
+
+        let (parent, name) = split_path(from);
+        let item = match self.group_mut(parent)?.remove(name) {
+            Some(item) => item,
+            None => return Err(format!("{} does not exist", from).into()),
+        };
+        self.insert(to, item)
+    }
+
+    /// Checks the edited configuration in the same way as it is checked when loaded and writes it.
+    ///
+    /// The file is written to a temporary file next to it first and then renamed over it, so that
+    /// a failed write does not lose the configuration. A symlink is followed and kept in place.
+    pub fn save(&self) -> Result<Config> {
+        let data = self.document.to_string();
+        let config = config::load_from_string(&data)?;
+
+        let path = fs::canonicalize(&self.path)?;
+        let tmp = path.with_extension("tmp");
+        let result = fs::write(&tmp, data)
+            .and_then(|_| fs::metadata(&path))
+            .and_then(|metadata| fs::set_permissions(&tmp, metadata.permissions()))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+
+        Ok(config)
+    }
+
+    fn insert(&mut self, path: &str, item: Item) -> Result<()> {
+        self.check_new_path(path)?;
+
+        let (parent, name) = split_path(path);
+        self.group_or_create(parent)?.insert(name, item);
+        Ok(())
+    }
+
+    /// Checks that an item can be inserted at the path: it must not exist yet, and every existing
+    /// group on the way must be a group rather than a profile, whose table would take the item
+    /// as an unknown field.
+    fn check_new_path(&self, path: &str) -> Result<()> {
+        let parts: Vec<_> = path.split('.').collect();
+        if parts.iter().any(|part| part.trim().is_empty()) {
+            return Err(format!("invalid name {}", path).into());
+        }
+
+        let mut group: &TableLike = self.document.as_table();
+        for (i, part) in parts.iter().enumerate() {
+            let item = match group.get(part) {
+                Some(item) => item,
+                None => return Ok(()),
+            };
+            if i == parts.len() - 1 {
+                return Err(format!("{} already exists", path).into());
+            }
+            if is_profile(item) {
+                return Err(ErrorKind::ProfileUsedAsGroup(parts[..i + 1].join("."), parts[i + 1].into()).into());
+            }
+            group = match item.as_table_like() {
+                Some(subgroup) => subgroup,
+                None => return Err(format!("{} is not a group", parts[..i + 1].join(".")).into()),
+            };
+        }
+        Ok(())
+    }
+
+    fn chain(&mut self, profile: &str) -> Result<Chain> {
+        let (parent, name) = split_path(profile);
+        let item = match self.group_mut(parent)?.get_mut(name) {
+            Some(item) => item,
+            None => return Err(format!("profile {} does not exist", profile).into()),
+        };
+
+        // The table form keeps the chain under the `chain` key
+        let item = if item.as_table_like().map_or(false, |t| t.contains_key("chain")) {
+            item.as_table_like_mut().unwrap().get_mut("chain").unwrap()
+        } else {
+            item
+        };
+
+        match *item {
+            Item::Value(Value::Array(ref mut array)) => Ok(Chain::Array(array)),
+            Item::ArrayOfTables(ref mut tables) => Ok(Chain::Tables(tables)),
+            _ => Err(format!("{} is not a profile", profile).into()),
+        }
+    }
+
+    fn group_mut(&mut self, path: &str) -> Result<&mut TableLike> {
+        let mut group: &mut TableLike = self.document.as_table_mut();
+        for part in path.split('.').filter(|p| !p.is_empty()) {
+            group = match group.get_mut(part).and_then(|item| item.as_table_like_mut()) {
+                Some(subgroup) => subgroup,
+                None => return Err(format!("group {} does not exist", path).into()),
+            };
+        }
+        Ok(group)
+    }
+
+    fn group_or_create(&mut self, path: &str) -> Result<&mut TableLike> {
+        let mut group: &mut TableLike = self.document.as_table_mut();
+        for part in path.split('.').filter(|p| !p.is_empty()) {
+            if !group.contains_key(part) {
+                let mut table = Table::new();
+                table.set_implicit(true);
+                group.insert(part, Item::Table(table));
+            }
+            group = match group.get_mut(part).and_then(|item| item.as_table_like_mut()) {
+                Some(subgroup) => subgroup,
+                None => return Err(format!("{} is not a group", path).into()),
+            };
+        }
+        Ok(group)
+    }
+}
+
+/// Returns `true` if the item is a profile in the array, array of tables or table form.
+fn is_profile(item: &Item) -> bool {
+    match *item {
+        Item::Value(Value::Array(_)) | Item::ArrayOfTables(_) => true,
+        _ => item.as_table_like().map_or(false, |t| t.contains_key("chain")),
+    }
+}
+
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('.') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+fn read_fields(table: &TableLike) -> JumpFields {
+    let string = |key: &str| table.get(key).and_then(|i| i.as_str()).unwrap_or_default().to_owned();
+    let integer = |key: &str| table.get(key).and_then(|i| i.as_integer()).map(|i| i.to_string()).unwrap_or_default();
+    let boolean = |key: &str| table.get(key).and_then(|i| i.as_bool());
+
+    let tunnel = match table.get("tunnel") {
+        Some(item) if item.is_str() => item.as_str().unwrap().into(),
+        Some(item) if item.is_integer() => item.as_integer().unwrap().to_string(),
+        Some(item) if item.is_bool() => item.as_bool().unwrap().to_string(),
+        Some(item) if item.is_table_like() => {
+            let tunnel = item.as_table_like().unwrap();
+            let part = |key: &str| tunnel.get(key).map(|i| match i.as_integer() {
+                Some(port) => port.to_string(),
+                None => i.as_str().unwrap_or_default().into(),
+            }).unwrap_or_default();
+            format!("{}:{}|{}:{}", part("local_host"), part("local_port"), part("remote_host"), part("remote_port"))
+        }
+        _ => String::new(),
+    };
+
+    JumpFields {
+        host: string("host"),
+        port: integer("port"),
+        user: string("user"),
+        key: string("key"),
+        tunnel,
+        verbose: boolean("verbose"),
+        agent_passthrough: boolean("agent_passthrough"),
+        no_command: boolean("no_command"),
+    }
+}
+
+/// Writes the fields which changed, leaving the rest of the table intact.
+fn write_fields(table: &mut TableLike, old: &JumpFields, new: &JumpFields) -> Result<()> {
+    fn set<V: Into<Value>>(table: &mut TableLike, key: &str, value: Option<V>) {
+        match value {
+            Some(value) => { table.insert(key, toml_edit::value(value)); }
+            None => { table.remove(key); }
+        }
+    }
+
+    let string = |s: &str| if s.is_empty() { None } else { Some(s.to_owned()) };
+
+    if old.host != new.host {
+        set(table, "host", string(&new.host));
+    }
+    if old.port != new.port {
+        set(table, "port", parse_port(&new.port)?);
+    }
+    if old.user != new.user {
+        set(table, "user", string(&new.user));
+    }
+    if old.key != new.key {
+        set(table, "key", string(&new.key));
+    }
+    if old.tunnel != new.tunnel {
+        match tunnel_value(&new.tunnel) {
+            Some(value) => { table.insert("tunnel", toml_edit::value(value)); }
+            None => { table.remove("tunnel"); }
+        }
+    }
+    if old.verbose != new.verbose {
+        set(table, "verbose", new.verbose);
+    }
+    if old.agent_passthrough != new.agent_passthrough {
+        set(table, "agent_passthrough", new.agent_passthrough);
+    }
+    if old.no_command != new.no_command {
+        set(table, "no_command", new.no_command);
+    }
+
+    Ok(())
+}
+
+/// Returns the value of a new jump: the host alone if possible, or an inline table.
+fn jump_value(jump: &JumpFields) -> Result<Value> {
+    if jump.is_host_only() {
+        return Ok(jump.host.clone().into());
+    }
+
+    let mut table = InlineTable::new();
+    write_fields(&mut table, &JumpFields::default(), jump)?;
+    table.fmt();
+    Ok(Value::InlineTable(table))
+}
+
+fn parse_port(s: &str) -> Result<Option<i64>> {
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+    match s.trim().parse::<u16>() {
+        Ok(port) => Ok(Some(port as i64)),
+        Err(e) => Err(format!("port {} is invalid: {}", s, e).into()),
+    }
+}
+
+/// Tunnels are written as `false`, a port number or a tunnel string, whichever the value looks like.
+fn tunnel_value(s: &str) -> Option<Value> {
+    let s = s.trim();
+    if s.is_empty() {
+        None
+    } else if s == "false" {
+        Some(false.into())
+    } else if let Ok(port) = s.parse::<u16>() {
+        Some((port as i64).into())
+    } else {
+        Some(s.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use super::*;
+    use testing::TempDir;
+
+    const CONFIG: &str = r#"# Servers
+[prod]
+# The main one
+app = ["bastion", { host = "app", port = 2222 }]
+
+[[prod.db]]
+host = "db"  # primary
+user = "postgres"
+
+[staging]
+app = { description = "Staging", chain = ["staging-app"] }
+"#;
+
+    fn editor() -> ConfigEditor {
+        ConfigEditor::from_string(Path::new("config.toml"), CONFIG).unwrap()
+    }
+
+    #[test]
+    fn test_set_jumps() {
+        let mut editor = editor();
+
+        let mut jumps = editor.jumps("prod.app").unwrap();
+        assert_eq!(jumps[1], JumpFields { host: "app".into(), port: "2222".into(), ..JumpFields::default() });
+
+        jumps[1].port = "".into();
+        jumps[1].verbose = Some(true);
+        jumps.push(JumpFields { host: "internal".into(), ..JumpFields::default() });
+        editor.set_jumps("prod.app", &jumps).unwrap();
+
+        let mut jumps = editor.jumps("prod.db").unwrap();
+        jumps[0].user = "admin".into();
+        editor.set_jumps("prod.db", &jumps).unwrap();
+
+        let jumps = vec![JumpFields { host: "staging-app".into(), tunnel: "8080".into(), ..JumpFields::default() }];
+        editor.set_jumps("staging.app", &jumps).unwrap();
+
+        assert_eq!(editor.document.to_string(), r#"# Servers
+[prod]
+# The main one
+app = ["bastion", { host = "app", verbose = true }, "internal"]
+
+[[prod.db]]
+host = "db"  # primary
+user = "admin"
+
+[staging]
+app = { description = "Staging", chain = [{ host = "staging-app", tunnel = 8080 }] }
+"#);
+
+        let jumps = vec![JumpFields { host: "app".into(), port: "http".into(), ..JumpFields::default() }];
+        assert_eq!(
+            editor.set_jumps("prod.app", &jumps).unwrap_err().to_string(),
+            "port http is invalid: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn test_add_remove_rename() {
+        let mut editor = editor();
+
+        editor.add("dev.local.vm", &[JumpFields { host: "vm".into(), ..JumpFields::default() }]).unwrap();
+        editor.rename("staging.app", "staging.web").unwrap();
+        editor.rename("prod.db", "db").unwrap();
+        editor.remove("prod.app").unwrap();
+
+        assert_eq!(editor.add("db", &[]).unwrap_err().to_string(), "db already exists");
+        assert_eq!(editor.remove("prod.web").unwrap_err().to_string(), "prod.web does not exist");
+        assert_eq!(editor.rename("staging", "staging.old").unwrap_err().to_string(), "cannot move staging into itself");
+        assert_eq!(
+            editor.rename("dev.local.vm", "staging.web.vm").unwrap_err().to_string(),
+            "staging.web is a profile, not a group, so it has no vm"
+        );
+        assert_eq!(
+            editor.add("db.replica", &[]).unwrap_err().to_string(),
+            "db is a profile, not a group, so it has no replica"
+        );
+
+        assert_eq!(editor.document.to_string(), r#"# Servers
+[prod]
+
+[[db]]
+host = "db"  # primary
+user = "postgres"
+
+[staging]
+web = { description = "Staging", chain = ["staging-app"] }
+
+[dev.local]
+vm = ["vm"]
+"#);
+
+        let config = config::load_from_string(&editor.document.to_string()).unwrap();
+        let paths: Vec<_> = config.root.iter_definitions().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["db", "dev.local.vm", "staging.web"]);
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = TempDir::new("edit");
+        let target = dir.write("config.toml", "[prod]\napp = [\"app\"]  # main\n\n[staging]\napp = [\"staging-app\"]\n", 0o600);
+        let link = dir.join("link.toml");
+        symlink(&target, &link).unwrap();
+
+        let mut editor = ConfigEditor::open(&link).unwrap();
+        editor.remove("staging").unwrap();
+        editor.save().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "[prod]\napp = [\"app\"]  # main\n");
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert!(!dir.join("config.tmp").exists());
+
+        // An invalid config is not written
+        editor.add("empty", &[]).unwrap();
+        assert_eq!(editor.save().unwrap_err().to_string(), "empty has no jumps");
+        assert!(!fs::read_to_string(&target).unwrap().contains("empty"));
+    }
+}
@@ -1,9 +1,16 @@
 use std::fmt;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
 
 use itertools::Itertools;
-use exec;
 
-use config::{ConfigDefinition, Tunnel, State};
+use config::{proxied_jumps, ConfigDefinition, SingleJump, Tunnel, State, Transport};
+use dirs;
+
+/// How long master connections of multiplexed profiles stay open after the last session ends.
+const CONTROL_PERSIST: &str = "10m";
 
 pub struct Execution {
     definition: ConfigDefinition,
@@ -20,28 +27,59 @@ impl From<ConfigDefinition> for Execution {
 impl Execution {
     pub fn prepare(&mut self) {
         let mut parts = Vec::new();
+        let proxied = proxied_jumps(&self.definition.chain);
+
+        for (i, jump) in self.definition.chain.iter().enumerate().skip(proxied) {
+            // Proxied jumps are not nested, so they do not count for quoting
+            let depth = i - proxied;
+            let ssh = jump.ssh_path.clone().unwrap_or_else(|| "ssh".into());
+
+            let mut cmd = match jump.transport.unwrap_or(Transport::Ssh) {
+                Transport::Ssh => vec![ssh],
+                // Disable the monitoring port, so that autossh restarts ssh only when it exits
+                Transport::Autossh => vec!["autossh".into(), "-M".into(), "0".into()],
+                Transport::Mosh => {
+                    // Tunnels and other flags are refused when the config is loaded
+                    let mut mosh = vec!["mosh".into()];
+                    let options = connection_options(jump, "-p");
+                    if !options.is_empty() || jump.ssh_path.is_some() {
+                        let ssh: Vec<_> = Some(ssh).into_iter().chain(options)
+                            .map(|arg| shell_quote(&arg).into_owned())
+                            .collect();
+                        mosh.push(format!("--ssh={}", ssh.join(" ")));
+                    }
+                    mosh.push(jump.destination());
+                    mosh
+                }
+            };
 
-        for jump in &self.definition.chain {
-            let mut cmd = vec!["ssh".into()];
-
-            if jump.verbose {
-                cmd.push("-v".into());
+            if let Some(Transport::Mosh) = jump.transport {
+                parts.push(quote_nested(cmd, depth));
+                continue;
             }
 
-            if let State::Enabled(_) = jump.agent_passthrough {
-                cmd.push("-A".into());
+            // Only the connection made by the local ssh can be shared
+            if depth == 0 && self.definition.multiplex {
+                cmd.extend(vec![
+                    "-o".into(), "ControlMaster=auto".into(),
+                    "-o".into(), control_path_option(),
+                    "-o".into(), format!("ControlPersist={}", CONTROL_PERSIST),
+                ]);
             }
 
-            if let Some(port) = jump.port {
-                cmd.push("-p".into());
-                cmd.push(port.to_string());
+            if depth == 0 {
+                if let Some(option) = proxy_option(&self.definition.chain[..i + 1]) {
+                    cmd.push("-o".into());
+                    cmd.push(option);
+                }
             }
 
-            if let Some(ref key) = jump.key {
-                cmd.push("-i".into());
-                cmd.push(key.clone());
+            if let State::Enabled(_) = jump.agent_passthrough {
+                cmd.push("-A".into());
             }
 
+            cmd.extend(connection_options(jump, "-p"));
+
             if let State::Enabled(ref tunnel) = jump.tunnel {
                 cmd.push("-L".into());
                 cmd.push(SshArg(tunnel).to_string());
@@ -57,7 +95,7 @@ impl Execution {
                 cmd.push("-N".into());
             }
 
-            parts.push(cmd);
+            parts.push(quote_nested(cmd, depth));
         }
 
         self.command_parts = parts;
@@ -73,23 +111,214 @@ impl Execution {
             .join(" -t \\\n  ")
     }
 
-    pub fn run(mut self) {
+    /// Returns the full command line, starting with the program to run.
+    pub fn args(&mut self) -> Vec<OsString> {
         if self.command_parts.is_empty() {
             self.prepare();
         }
 
-        let args: Vec<_> = self.command_parts.into_iter()
+        self.command_parts.iter().cloned()
             .intersperse(vec!["-t".into()])
             .flatten()
+            .map(OsString::from)
+            .collect()
+    }
+
+    /// Returns the command sending a control request, like `check` or `exit`, to the master
+    /// connection of the first jump run by the local ssh, or `None` if the profile is not multiplexed.
+    pub fn control_command(&self, request: &str) -> Option<Vec<String>> {
+        if !self.definition.multiplex {
+            return None;
+        }
+        let jump = self.definition.chain.get(proxied_jumps(&self.definition.chain))?;
+
+        let mut cmd = vec![
+            jump.ssh_path.clone().unwrap_or_else(|| "ssh".into()),
+            "-O".into(), request.into(),
+            "-o".into(), control_path_option(),
+        ];
+        if let Some(port) = jump.port {
+            cmd.push("-p".into());
+            cmd.push(port.to_string());
+        }
+        cmd.push(jump.destination());
+
+        Some(cmd)
+    }
+
+    /// Returns `false` if the last jump cannot execute commands non-interactively, i.e. if it
+    /// only forwards ports (`-N`) or uses mosh.
+    pub fn accepts_command(&self) -> bool {
+        match self.definition.chain.last() {
+            Some(jump) => jump.no_command != State::Enabled(()) && jump.transport != Some(Transport::Mosh),
+            None => false,
+        }
+    }
+
+    /// Returns the arguments which run `command` non-interactively on the last host of the chain.
+    ///
+    /// Jumps are nested without `-t`, and the command is quoted once for every intermediate shell
+    /// it passes through.
+    pub fn remote_command_args(&mut self, command: &str) -> Vec<String> {
+        if self.command_parts.is_empty() {
+            self.prepare();
+        }
+
+        let mut command = command.to_owned();
+        for _ in 1..self.command_parts.len() {
+            command = shell_quote(&command).into_owned();
+        }
+
+        let mut args: Vec<String> = self.command_parts.iter()
+            .flat_map(|part| part.iter().cloned())
             .collect();
+        args.push(command);
+        args
+    }
+}
+
+/// Returns the directory holding the control sockets of multiplexed profiles.
+pub fn control_dir() -> PathBuf {
+    dirs::runtime_dir().join("mux")
+}
+
+/// `%C` is a hash of the local and remote host names, the port and the user.
+fn control_path_option() -> String {
+    format!("ControlPath={}", control_dir().join("%C").display())
+}
+
+/// Quotes arguments of the jump `idx` once for every shell they pass through.
+///
+/// Arguments of the first jump are passed to the local `ssh` directly, while arguments of
+/// each subsequent jump are interpreted by the shells of all previous hosts.
+fn quote_nested(args: Vec<String>, idx: usize) -> Vec<String> {
+    args.into_iter()
+        .map(|arg| (0..idx).fold(arg, |arg, _| shell_quote(&arg).into_owned()))
+        .collect()
+}
+
+/// Returns options for connecting to the jump: verbosity, port, key and environment variables.
+///
+/// These are shared by interactive connections, proxies and transfers. The port option is passed
+/// explicitly because `scp` uses `-P` instead of `-p`.
+pub(crate) fn connection_options(jump: &SingleJump, port_flag: &str) -> Vec<String> {
+    let mut options = Vec::new();
+
+    if jump.verbose {
+        options.push("-v".into());
+    }
+
+    if let Some(port) = jump.port {
+        options.push(port_flag.into());
+        options.push(port.to_string());
+    }
+
+    if let Some(ref key) = jump.key {
+        options.push("-i".into());
+        options.push(key.clone());
+    }
+
+    if !jump.set_env.is_empty() {
+        options.push("-o".into());
+        options.push(set_env_option(&jump.set_env));
+    }
+
+    if !jump.send_env.is_empty() {
+        options.push("-o".into());
+        options.push(format!("SendEnv={}", jump.send_env.join(" ")));
+    }
 
-        println!("{}", args.join(" "));
+    options
+}
+
+/// Formats the variables as the `SetEnv=...` option, quoting assignments the way ssh_config expects.
+fn set_env_option(env: &BTreeMap<String, String>) -> String {
+    let mut vars = env.iter().map(|(name, value)| {
+        let var = format!("{}={}", name, value);
+        if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\' || c == '\'') {
+            var
+        } else {
+            format!("\"{}\"", var.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+    });
+    format!("SetEnv={}", vars.join(" "))
+}
+
+/// Returns the ssh options for tools like scp and rsync, which start ssh on the local machine
+/// themselves: the connection options of the last jump and a `ProxyCommand` through all
+/// previous jumps.
+///
+/// The port option is passed explicitly because `scp` uses `-P` instead of `-p`.
+pub fn direct_options(chain: &[SingleJump], port_flag: &str) -> Vec<String> {
+    let mut options = chain.last().map(|target| connection_options(target, port_flag)).unwrap_or_default();
+    if let Some(proxy) = proxy_option(chain) {
+        options.push("-o".into());
+        options.push(proxy);
+    }
+    options
+}
 
-        let command = args[0].clone();
-        let error = exec::execvp(command, args);
+/// Returns the `ProxyCommand=...` option which connects to the last jump of the chain through
+/// all previous jumps, so that every ssh process runs on the local machine.
+///
+/// Returns `None` if there is only one jump.
+fn proxy_option(chain: &[SingleJump]) -> Option<String> {
+    proxy_command(chain).map(|command| format!("ProxyCommand={}", command.replace('%', "%%")))
+}
 
-        eprintln!("Failed to run SSH: {}", error);
-        ::std::process::exit(1);
+fn proxy_command(chain: &[SingleJump]) -> Option<String> {
+    let (target, proxies) = match chain.split_last() {
+        Some((target, proxies)) if !proxies.is_empty() => (target, proxies),
+        _ => return None,
+    };
+    let via = proxies.last().unwrap();
+
+    // mosh and autossh are refused on proxied jumps, and ssh itself only forwards the connection
+    let mut command = vec![via.ssh_path.clone().unwrap_or_else(|| "ssh".into())];
+    command.extend(connection_options(via, "-p"));
+    if let Some(option) = proxy_option(proxies) {
+        command.push("-o".into());
+        command.push(option);
+    }
+    command.push("-W".into());
+    command.push(format!("{}:{}", target.host, target.port.unwrap_or(22)));
+    command.push(via.destination());
+
+    Some(shell_join(&command))
+}
+
+/// A value which is not written in the config but derived by normalization, with the rule which produced it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Derivation {
+    RemoteHostDefaulted,
+    LocalPortMirrored(u16),
+    RemotePortMirrored(u16),
+    /// The tunnel is copied from the jump with the given index
+    TunnelInherited(usize),
+    AgentPassthroughAdded,
+    NoCommandAdded,
+    /// `SendEnv` patterns copied from later jumps
+    SendEnvPropagated(Vec<String>),
+}
+
+impl Derivation {
+    pub fn describe(&self) -> String {
+        match *self {
+            Derivation::RemoteHostDefaulted =>
+                "tunnel remote host set to localhost because it is not specified".into(),
+            Derivation::LocalPortMirrored(port) =>
+                format!("tunnel local port set to {} because only the remote port is specified", port),
+            Derivation::RemotePortMirrored(port) =>
+                format!("tunnel remote port set to {} because only the local port is specified", port),
+            Derivation::TunnelInherited(from) =>
+                format!("tunnel inherited from jump {} because this jump does not disable it", from + 1),
+            Derivation::AgentPassthroughAdded =>
+                "-A added because the jump has a tunnel and is not the last one".into(),
+            Derivation::NoCommandAdded =>
+                "-N added because the last jump has a tunnel".into(),
+            Derivation::SendEnvPropagated(ref patterns) =>
+                format!("SendEnv={} added because a later jump sends it, so it must pass through this jump", patterns.join(" ")),
+        }
     }
 }
 
@@ -99,24 +328,36 @@ impl Execution {
 /// * Expands tunnelspecs, e.g. 12345 -> ":12345|localhost:12345"
 /// * Propagates tunnelspecs down the chains
 /// * Adds flags responsible for -A and -N where appropriate
-fn normalize_definition(mut definition: ConfigDefinition) -> ConfigDefinition {
+/// * Propagates SendEnv patterns up the chains
+pub fn normalize_definition(definition: ConfigDefinition) -> ConfigDefinition {
+    normalize_definition_explained(definition).0
+}
+
+/// Normalizes the definition, also returning the values derived for each jump.
+pub fn normalize_definition_explained(mut definition: ConfigDefinition) -> (ConfigDefinition, Vec<Vec<Derivation>>) {
+    let mut derivations = Vec::new();
     let mut last_tunnel: Option<Tunnel> = None;
     let chain_len = definition.chain.len();
     for (i, jump) in definition.chain.iter_mut().enumerate() {
+        let mut derived = Vec::new();
+
         // Fix the tunnelspec if it is present:
         //   1. Propagate local or remote port to its missing counterpart
         //   2. Add the default "localhost" value if the remote host is absent
         if let State::Enabled(ref mut tunnel) = jump.tunnel {
             if tunnel.remote_host.is_none() {
                 tunnel.remote_host = Some("localhost".into());
+                derived.push(Derivation::RemoteHostDefaulted);
             }
 
             if tunnel.local_port.is_none() {
                 tunnel.local_port = tunnel.remote_port;
+                derived.extend(tunnel.local_port.map(Derivation::LocalPortMirrored));
             }
 
             if tunnel.remote_port.is_none() {
                 tunnel.remote_port = tunnel.local_port;
+                derived.extend(tunnel.remote_port.map(Derivation::RemotePortMirrored));
             }
         }
 
@@ -124,6 +365,7 @@ fn normalize_definition(mut definition: ConfigDefinition) -> ConfigDefinition {
         if let State::Unset = jump.tunnel {
             if let Some(ref last_tunnel) = last_tunnel {
                 jump.tunnel = State::Enabled(last_tunnel.clone());
+                derived.push(Derivation::TunnelInherited(i - 1));
             }
         }
 
@@ -133,6 +375,7 @@ fn normalize_definition(mut definition: ConfigDefinition) -> ConfigDefinition {
             if i < chain_len - 1 {
                 if let State::Unset = jump.agent_passthrough {
                     jump.agent_passthrough = State::Enabled(());
+                    derived.push(Derivation::AgentPassthroughAdded);
                 }
             }
 
@@ -140,6 +383,7 @@ fn normalize_definition(mut definition: ConfigDefinition) -> ConfigDefinition {
             if i == chain_len - 1 {
                 if let State::Unset = jump.no_command {
                     jump.no_command = State::Enabled(());
+                    derived.push(Derivation::NoCommandAdded);
                 }
             }
 
@@ -150,9 +394,49 @@ fn normalize_definition(mut definition: ConfigDefinition) -> ConfigDefinition {
             // Remove the last tunnel if it is not configured/disabled in this jump
             last_tunnel = None;
         }
+
+        derivations.push(derived);
+    }
+
+    // A variable reaches a jump only if all nested jumps before it send it too
+    let proxied = proxied_jumps(&definition.chain);
+    let mut sent: Vec<String> = Vec::new();
+    for (jump, derived) in definition.chain.iter_mut().zip(&mut derivations).skip(proxied).rev() {
+        let missing: Vec<_> = sent.iter().filter(|p| !jump.send_env.contains(p)).cloned().collect();
+        for pattern in &jump.send_env {
+            if !sent.contains(pattern) {
+                sent.push(pattern.clone());
+            }
+        }
+        if !missing.is_empty() {
+            jump.send_env.extend(missing.iter().cloned());
+            derived.push(Derivation::SendEnvPropagated(missing));
+        }
+    }
+
+    (definition, derivations)
+}
+
+/// Joins the arguments into a line which can be pasted into a POSIX shell.
+pub fn shell_join<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter().map(|arg| shell_quote(arg.as_ref())).join(" ")
+}
+
+/// Quotes the string for a POSIX shell, unless it consists only of safe characters.
+///
+/// `~` is considered safe, so that paths like `~/.ssh/key` are still expanded by the remote shell.
+pub(crate) fn shell_quote(s: &str) -> Cow<str> {
+    let is_safe = |c: char| c.is_alphanumeric() || "-_./:@%+=,~".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.into()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''")).into()
     }
+}
 
-    definition
+/// Formats the tunnel as the argument of `-L`.
+pub fn forward_spec(tunnel: &Tunnel) -> String {
+    SshArg(tunnel).to_string()
 }
 
 struct SshArg<'a>(&'a Tunnel);
@@ -182,3 +466,140 @@ impl<'a> fmt::Display for SshArg<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::KeyLocation;
+
+    #[test]
+    fn test_nested_quoting() {
+        let definition = ConfigDefinition {
+            chain: vec![
+                SingleJump { key: Some("~/keys/vps key".into()), ..SingleJump::new("public-vps.cc".into()) },
+                SingleJump { key: Some("~/keys/home server".into()), ..SingleJump::new("home-server.vpn".into()) },
+                SingleJump {
+                    user: Some("me".into()),
+                    key: Some("~/.ssh/db".into()),
+                    tunnel: State::Enabled(Tunnel {
+                        local_host: Some("*".into()),
+                        local_port: Some(5432),
+                        remote_host: None,
+                        remote_port: None,
+                    }),
+                    ..SingleJump::new("db".into())
+                },
+            ],
+            ..ConfigDefinition::default()
+        };
+
+        // The first jump is run locally without a shell, while safe arguments are never quoted
+        let args: Vec<OsString> = vec![
+            "ssh", "-i", "~/keys/vps key", "public-vps.cc", "-t",
+            "ssh", "-i", "'~/keys/home server'", "home-server.vpn", "-t",
+            "ssh", "-i", "~/.ssh/db", "-L", "''\\''*:5432:localhost:5432'\\'''", "me@db", "-N",
+        ].into_iter().map(OsString::from).collect();
+        assert_eq!(Execution::from(definition).args(), args);
+    }
+
+    #[test]
+    fn test_transports() {
+        let definition = ConfigDefinition {
+            chain: vec![
+                SingleJump { transport: Some(Transport::Autossh), ..SingleJump::new("bastion".into()) },
+                SingleJump {
+                    port: Some(2222),
+                    key: Some("~/.ssh/app key.pem".into()),
+                    transport: Some(Transport::Mosh),
+                    ..SingleJump::new("app".into())
+                },
+            ],
+            ..ConfigDefinition::default()
+        };
+
+        assert_eq!(
+            Execution::from(definition.clone()).command_line(),
+            "autossh -M 0 bastion -t \\\n  mosh '--ssh=ssh -p 2222 -i '\\''~/.ssh/app key.pem'\\''' app"
+        );
+
+        let args: Vec<OsString> = vec![
+            "autossh", "-M", "0", "bastion", "-t",
+            "mosh", "'--ssh=ssh -p 2222 -i '\\''~/.ssh/app key.pem'\\'''", "app",
+        ].into_iter().map(OsString::from).collect();
+        assert_eq!(Execution::from(definition).args(), args);
+    }
+
+    #[test]
+    fn test_key_locations() {
+        let definition = ConfigDefinition {
+            chain: vec![
+                SingleJump { port: Some(2222), ..SingleJump::new("bastion".into()) },
+                SingleJump {
+                    key: Some("~/.ssh/app".into()),
+                    key_location: Some(KeyLocation::Local),
+                    ..SingleJump::new("app".into())
+                },
+                SingleJump { key: Some("/home/me/.ssh/db key".into()), ..SingleJump::new("db".into()) },
+            ],
+            multiplex: true,
+            ..ConfigDefinition::default()
+        };
+        assert_eq!(proxied_jumps(&definition.chain), 1);
+
+        let mut execution = Execution::from(definition);
+        let args = execution.args();
+        let args: Vec<_> = args.iter().map(|arg| arg.to_str().unwrap()).collect();
+        assert_eq!(&args[..1], &["ssh"]);
+        assert_eq!(&args[7..], &[
+            "-o", "ProxyCommand=ssh -p 2222 -W app:22 bastion",
+            "-i", "~/.ssh/app", "app", "-t",
+            "ssh", "-i", "'/home/me/.ssh/db key'", "db",
+        ]);
+
+        let control = execution.control_command("check").unwrap();
+        assert_eq!(control.last().map(String::as_str), Some("app"));
+    }
+
+    #[test]
+    fn test_proxy_ssh_path() {
+        let chain = vec![
+            SingleJump { ssh_path: Some("/opt/ssh/bin/ssh".into()), ..SingleJump::new("bastion".into()) },
+            SingleJump::new("app".into()),
+        ];
+        assert_eq!(direct_options(&chain, "-p"), vec!["-o", "ProxyCommand=/opt/ssh/bin/ssh -W app:22 bastion"]);
+    }
+
+    #[test]
+    fn test_env() {
+        let env = vec![("KUBE_CONTEXT".to_owned(), "prod eu".to_owned()), ("TERM".into(), "xterm".into())];
+        let app = SingleJump {
+            set_env: env.into_iter().collect(),
+            send_env: vec!["LC_*".into(), "LANG".into()],
+            ..SingleJump::new("app".into())
+        };
+        let definition = ConfigDefinition {
+            chain: vec![
+                SingleJump { send_env: vec!["LANG".into()], ..SingleJump::new("bastion".into()) },
+                SingleJump::new("gateway".into()),
+                app.clone(),
+            ],
+            ..ConfigDefinition::default()
+        };
+
+        let (normalized, derivations) = normalize_definition_explained(definition);
+        let send_env: Vec<_> = normalized.chain.iter().map(|j| j.send_env.join(" ")).collect();
+        assert_eq!(send_env, vec!["LANG LC_*", "LC_* LANG", "LC_* LANG"]);
+        assert_eq!(derivations[0], vec![Derivation::SendEnvPropagated(vec!["LC_*".into()])]);
+        assert_eq!(derivations[2], vec![]);
+
+        let definition = ConfigDefinition {
+            chain: vec![SingleJump::new("bastion".into()), app],
+            ..ConfigDefinition::default()
+        };
+        let args: Vec<OsString> = vec![
+            "ssh", "-o", "SendEnv=LC_* LANG", "bastion", "-t",
+            "ssh", "-o", "'SetEnv=\"KUBE_CONTEXT=prod eu\" TERM=xterm'", "-o", "'SendEnv=LC_* LANG'", "app",
+        ].into_iter().map(OsString::from).collect();
+        assert_eq!(Execution::from(definition).args(), args);
+    }
+}
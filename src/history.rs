@@ -0,0 +1,147 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dirs;
+use state_files;
+
+/// A single launch of a profile.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub profile: String,
+    pub dry_run: bool,
+    /// Exit status of the connection; it is unknown when sshc replaces itself with ssh.
+    pub status: Option<i32>,
+}
+
+impl Entry {
+    pub fn new(profile: String, dry_run: bool, status: Option<i32>) -> Entry {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Entry { timestamp, profile, dry_run, status, }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.profile,
+            if self.dry_run { "dry-run" } else { "run" },
+            self.status.map(|s| s.to_string()).unwrap_or_else(|| "-".into())
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Entry> {
+        let mut parts = line.split('\t');
+        let timestamp = parts.next()?.parse().ok()?;
+        let profile = parts.next()?.to_owned();
+        let dry_run = match parts.next()? {
+            "dry-run" => true,
+            "run" => false,
+            _ => return None,
+        };
+        let status = match parts.next()? {
+            "-" => None,
+            s => Some(s.parse().ok()?),
+        };
+        Some(Entry { timestamp, profile, dry_run, status, })
+    }
+
+    /// Formats the entry as a line of `sshc history` output.
+    pub fn describe(&self) -> String {
+        let mut result = format!("{}  {}", format_timestamp(self.timestamp), self.profile);
+        if self.dry_run {
+            result.push_str("  (dry run)");
+        }
+        if let Some(status) = self.status {
+            result.push_str(&format!("  exit code {}", status));
+        }
+        result
+    }
+}
+
+fn history_file() -> PathBuf {
+    dirs::state_dir().join("history")
+}
+
+/// Appends the entry to the history file.
+pub fn record(entry: &Entry) {
+    let result = dirs::ensure_dir(&dirs::state_dir()).and_then(|_| {
+        let mut f = OpenOptions::new().create(true).append(true).open(history_file())?;
+        writeln!(f, "{}", entry.to_line())
+    });
+    state_files::report(result, "history", &history_file());
+}
+
+/// Loads all history entries, oldest first; malformed lines are skipped.
+pub fn load() -> io::Result<Vec<Entry>> {
+    let f = match File::open(history_file()) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(f).lines() {
+        entries.extend(Entry::from_line(&line?));
+    }
+    Ok(entries)
+}
+
+/// Returns up to `limit` distinct profiles which were launched most recently, latest first.
+pub fn recent_profiles(entries: &[Entry], limit: usize) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for entry in entries.iter().rev() {
+        if result.len() == limit {
+            break;
+        }
+        if !result.contains(&entry.profile) {
+            result.push(entry.profile.clone());
+        }
+    }
+    result
+}
+
+/// Formats the timestamp as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = ((timestamp / 86400) as i64, timestamp % 86400);
+
+    // Converts days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries() {
+        let entries: Vec<_> = [
+            "1500000000\ta.b\trun\t-",
+            "1500000060\tc\tdry-run\t-",
+            "garbage",
+            "1500000120\ta.b\trun\t255",
+        ].iter().filter_map(|line| Entry::from_line(line)).collect();
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].to_line(), "1500000120\ta.b\trun\t255");
+        assert_eq!(entries[2].describe(), "2017-07-14 02:42:00  a.b  exit code 255");
+        assert_eq!(recent_profiles(&entries, 5), vec!["a.b".to_owned(), "c".into()]);
+        assert_eq!(recent_profiles(&entries, 1), vec!["a.b".to_owned()]);
+    }
+}
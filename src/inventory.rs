@@ -0,0 +1,142 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde_json;
+use sha1::Sha1;
+
+use dirs;
+use value::Value;
+
+/// Parses a duration like `90`, `30s`, `10m`, `2h` or `1d`; plain numbers are seconds.
+pub fn parse_ttl(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("expected a number with an optional s, m, h or d suffix, got {}", s)),
+    };
+    match number.parse::<u64>() {
+        Ok(n) => Ok(Duration::from_secs(n * multiplier)),
+        Err(_) => Err(format!("expected a number with an optional s, m, h or d suffix, got {}", s)),
+    }
+}
+
+/// Returns the JSON output of the inventory command, reusing its cached output while it is
+/// younger than `ttl`. A zero `ttl` disables caching.
+pub fn fetch(command: &str, ttl: Duration) -> Result<Value, String> {
+    fetch_cached(command, ttl, &dirs::cache_dir().join("inventory"))
+}
+
+fn fetch_cached(command: &str, ttl: Duration, cache_dir: &Path) -> Result<Value, String> {
+    // The name must not change between builds, which rules out the hashers of std
+    let mut hash = Sha1::new();
+    hash.update(command.as_bytes());
+    let cache = cache_dir.join(format!("{}.json", hash.digest()));
+
+    let caching = ttl != Duration::from_secs(0);
+    if caching {
+        let fresh = fs::metadata(&cache).and_then(|m| m.modified())
+            .map(|modified| modified.elapsed().map(|age| age < ttl).unwrap_or(false))
+            .unwrap_or(false);
+        if fresh {
+            // A broken cache file is simply replaced
+            let cached = fs::read_to_string(&cache).ok()
+                .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok());
+            if let Some(value) = cached {
+                return Ok(value.into());
+            }
+        }
+    }
+
+    let output = run(command)?;
+    let value: serde_json::Value = serde_json::from_str(&output)
+        .map_err(|e| format!("{} printed invalid JSON: {}", command, e))?;
+
+    if caching {
+        // Failing to cache only means running the command again next time
+        let _ = write_cache(cache_dir, &cache, &output);
+    }
+
+    Ok(value.into())
+}
+
+fn run(command: &str) -> Result<String, String> {
+    let output = Command::new("sh").arg("-c").arg(command)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", command, e))?;
+
+    if !output.status.success() {
+        let status = match output.status.code() {
+            Some(code) => format!("exit code {}", code),
+            None => "a signal".into(),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        return Err(if stderr.is_empty() {
+            format!("{} failed with {}", command, status)
+        } else {
+            format!("{} failed with {}: {}", command, status, stderr)
+        });
+    }
+
+    String::from_utf8(output.stdout).map_err(|_| format!("{} printed invalid UTF-8", command))
+}
+
+fn write_cache(cache_dir: &Path, cache: &Path, output: &str) -> io::Result<()> {
+    dirs::ensure_dir(cache_dir)?;
+    let tmp = cache.with_extension("tmp");
+    fs::write(&tmp, output)?;
+    fs::rename(&tmp, cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TempDir;
+
+    #[test]
+    fn test_fetch() {
+        assert_eq!(parse_ttl("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_ttl("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_ttl("1w").is_err());
+
+        // An inventory which counts how many times it was run
+        let dir = TempDir::new("inventory");
+        let script = dir.script("inventory", "echo run >> \"$(dirname \"$0\")/runs\"\necho '{\"web\": [\"web-1\"]}'\n");
+        let command = script.display().to_string();
+        let cache_dir = dir.join("cache");
+        let runs = || fs::read_to_string(dir.join("runs")).unwrap().lines().count();
+
+        let expected = Value::Table(vec![
+            ("web".to_owned(), Value::Array(vec![Value::String("web-1".into())])),
+        ].into_iter().collect());
+
+        assert_eq!(fetch_cached(&command, Duration::from_secs(3600), &cache_dir), Ok(expected.clone()));
+        assert_eq!(fetch_cached(&command, Duration::from_secs(3600), &cache_dir), Ok(expected.clone()));
+        assert_eq!(runs(), 1);
+
+        assert_eq!(fetch_cached(&command, Duration::from_secs(0), &cache_dir), Ok(expected));
+        assert_eq!(runs(), 2);
+
+        // The cache is named after the SHA-1 of the command, so that other builds find it
+        assert!(fetch_cached("echo '{}'", Duration::from_secs(3600), &cache_dir).is_ok());
+        assert!(cache_dir.join("0d2672ced757e71ea6ea51c992aeae9fcda99907.json").exists());
+
+        assert_eq!(
+            fetch_cached("echo 'no such host' >&2; exit 3", Duration::from_secs(0), &cache_dir),
+            Err("echo 'no such host' >&2; exit 3 failed with exit code 3: no such host".into())
+        );
+        assert!(fetch_cached("echo nope", Duration::from_secs(0), &cache_dir).unwrap_err()
+            .starts_with("echo nope printed invalid JSON"));
+    }
+}
@@ -0,0 +1,377 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use base64;
+use itertools::Itertools;
+use sha1::Sha1;
+use shellexpand;
+
+use config::SingleJump;
+use dirs;
+
+/// A line of a known_hosts file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub marker: Option<Marker>,
+    pub hosts: Vec<HostPattern>,
+    pub key_type: String,
+    pub key: String,
+    /// File and line number, for messages
+    pub location: String,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HostPattern {
+    /// A pattern with `*` and `?` wildcards, optionally negated with `!`
+    Plain { pattern: String, negated: bool },
+    /// A `|1|salt|hash` entry, where the hash is HMAC-SHA1 of the host name keyed with the salt
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+/// What known_hosts files say about a host.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HostStatus {
+    /// The host has a single key of each listed type
+    Known(Vec<String>),
+    /// The host is trusted because its certificate is signed by a known authority
+    KnownByAuthority,
+    Unknown,
+    /// The host has several different keys of the same type
+    Conflicting { key_type: String, locations: Vec<String> },
+    /// A key of the host is listed as revoked
+    Revoked { key_type: String, location: String },
+}
+
+impl HostStatus {
+    pub fn is_ok(&self) -> bool {
+        match *self {
+            HostStatus::Known(_) | HostStatus::KnownByAuthority => true,
+            _ => false,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match *self {
+            HostStatus::Known(ref types) => format!("known ({})", types.join(", ")),
+            HostStatus::KnownByAuthority => "known through a certificate authority".into(),
+            HostStatus::Unknown => "unknown, ssh will ask to accept its key".into(),
+            HostStatus::Conflicting { ref key_type, ref locations } =>
+                format!("conflicting {} keys at {}", key_type, locations.join(", ")),
+            HostStatus::Revoked { ref key_type, ref location } =>
+                format!("its {} key is revoked at {}", key_type, location),
+        }
+    }
+}
+
+/// The name under which ssh looks a host up, and the files it looks in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub files: Vec<PathBuf>,
+}
+
+/// Resolves the host of the jump with `ssh -G`, so that `HostName`, `Port`, `HostKeyAlias` and
+/// the known_hosts files from ssh_config are taken into account like in a real connection.
+pub fn resolve(jump: &SingleJump) -> io::Result<Target> {
+    let ssh = jump.ssh_path.clone().unwrap_or_else(|| "ssh".into());
+    let mut command = Command::new(&ssh);
+    command.arg("-G");
+    if let Some(port) = jump.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    let output = command.arg(&jump.host).stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} -G failed: {}", ssh, String::from_utf8_lossy(&output.stderr).trim()),
+        ));
+    }
+
+    Ok(parse_target(&String::from_utf8_lossy(&output.stdout), &jump.host))
+}
+
+/// Reads the target from the output of `ssh -G`, which prints one lowercase option per line.
+fn parse_target(output: &str, host: &str) -> Target {
+    let mut hostname = host.to_owned();
+    let mut port = 22;
+    let mut alias = None;
+    let mut user_files = Vec::new();
+    let mut global_files = Vec::new();
+
+    for line in output.lines() {
+        let mut parts = line.splitn(2, ' ');
+        let (option, value) = match (parts.next(), parts.next()) {
+            (Some(option), Some(value)) => (option, value.trim()),
+            _ => continue,
+        };
+        let files = || value.split_whitespace().map(|f| PathBuf::from(shellexpand::tilde(f).into_owned())).collect::<Vec<_>>();
+        match option {
+            "hostname" => hostname = value.into(),
+            "port" => port = value.parse().unwrap_or(22),
+            "hostkeyalias" => alias = Some(value.to_owned()),
+            "userknownhostsfile" => user_files = files(),
+            "globalknownhostsfile" => global_files = files(),
+            _ => {}
+        }
+    }
+
+    let mut files = user_files;
+    files.extend(global_files);
+    if files.is_empty() {
+        files = default_files();
+    }
+
+    // ssh looks up an alias as it is, without the port
+    match alias {
+        Some(alias) => Target { host: alias, port: 22, files },
+        None => Target { host: hostname, port, files },
+    }
+}
+
+/// The user and the system known_hosts files, as used by ssh without configuration.
+fn default_files() -> Vec<PathBuf> {
+    vec![dirs::home_dir().join(".ssh/known_hosts"), "/etc/ssh/ssh_known_hosts".into()]
+}
+
+/// Loads the user and the system known_hosts files, skipping the ones which do not exist.
+pub fn load_default() -> io::Result<Vec<Entry>> {
+    load_all(&default_files())
+}
+
+/// Loads the files one after another, skipping the ones which do not exist.
+pub fn load_all(paths: &[PathBuf]) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for path in paths {
+        entries.extend(load(path)?);
+    }
+    Ok(entries)
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(parse(&contents, &path.display().to_string())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses the contents of a known_hosts file, skipping comments and malformed lines.
+pub fn parse(contents: &str, source: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let mut fields = line.split_whitespace().peekable();
+        let marker = match fields.peek() {
+            Some(&"@cert-authority") => Some(Marker::CertAuthority),
+            Some(&"@revoked") => Some(Marker::Revoked),
+            Some(field) if field.starts_with('#') => continue,
+            _ => None,
+        };
+        if marker.is_some() {
+            fields.next();
+        }
+
+        let (hosts, key_type, key) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(hosts), Some(key_type), Some(key)) => (hosts, key_type, key),
+            _ => continue,
+        };
+        let hosts = match parse_hosts(hosts) {
+            Some(hosts) => hosts,
+            None => continue,
+        };
+
+        entries.push(Entry {
+            marker,
+            hosts,
+            key_type: key_type.into(),
+            key: key.into(),
+            location: format!("{}:{}", source, idx + 1),
+        });
+    }
+    entries
+}
+
+fn parse_hosts(field: &str) -> Option<Vec<HostPattern>> {
+    if field.starts_with("|1|") {
+        let mut parts = field[3..].splitn(2, '|');
+        let salt = base64::decode(parts.next()?).ok()?;
+        let hash = base64::decode(parts.next()?).ok()?;
+        return Some(vec![HostPattern::Hashed { salt, hash }]);
+    }
+
+    Some(field.split(',').map(|pattern| if pattern.starts_with('!') {
+        HostPattern::Plain { pattern: pattern[1..].to_lowercase(), negated: true }
+    } else {
+        HostPattern::Plain { pattern: pattern.to_lowercase(), negated: false }
+    }).collect())
+}
+
+impl Entry {
+    /// Checks whether the entry applies to the host, taking negated patterns into account.
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        // Hosts on non-standard ports are listed as [host]:port
+        let name = match port {
+            22 => host.to_lowercase(),
+            _ => format!("[{}]:{}", host.to_lowercase(), port),
+        };
+
+        let mut matched = false;
+        for pattern in &self.hosts {
+            match *pattern {
+                HostPattern::Plain { ref pattern, negated } => if wildcard_match(pattern, &name) {
+                    if negated {
+                        return false;
+                    }
+                    matched = true;
+                },
+                HostPattern::Hashed { ref salt, ref hash } =>
+                    matched |= hmac_sha1(salt, name.as_bytes())[..] == hash[..],
+            }
+        }
+        matched
+    }
+}
+
+/// Finds out what the entries say about the host.
+pub fn status(entries: &[Entry], host: &str, port: u16) -> HostStatus {
+    let matching: Vec<_> = entries.iter().filter(|e| e.matches(host, port)).collect();
+
+    let keys: Vec<_> = matching.iter().filter(|e| e.marker.is_none()).collect();
+    for revoked in matching.iter().filter(|e| e.marker == Some(Marker::Revoked)) {
+        if keys.iter().any(|e| e.key == revoked.key) {
+            return HostStatus::Revoked { key_type: revoked.key_type.clone(), location: revoked.location.clone() };
+        }
+    }
+
+    let key_types: Vec<_> = keys.iter().map(|e| e.key_type.clone()).unique().collect();
+    for key_type in &key_types {
+        let of_type: Vec<_> = keys.iter().filter(|e| e.key_type == *key_type).collect();
+        if of_type.iter().map(|e| &e.key).unique().count() > 1 {
+            return HostStatus::Conflicting {
+                key_type: key_type.clone(),
+                locations: of_type.iter().map(|e| e.location.clone()).collect(),
+            };
+        }
+    }
+
+    if !key_types.is_empty() {
+        HostStatus::Known(key_types)
+    } else if matching.iter().any(|e| e.marker == Some(Marker::CertAuthority)) {
+        HostStatus::KnownByAuthority
+    } else {
+        HostStatus::Unknown
+    }
+}
+
+/// Matches `*` and `?` wildcards like ssh does.
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    fn matches(pattern: &[char], s: &[char]) -> bool {
+        match pattern.first() {
+            None => s.is_empty(),
+            Some(&'*') => (0..s.len() + 1).any(|i| matches(&pattern[1..], &s[i..])),
+            Some(&'?') => !s.is_empty() && matches(&pattern[1..], &s[1..]),
+            Some(c) => s.first() == Some(c) && matches(&pattern[1..], &s[1..]),
+        }
+    }
+    matches(&pattern, &s)
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        let mut hash = Sha1::new();
+        hash.update(key);
+        block[..20].copy_from_slice(&hash.digest().bytes());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    inner.update(message);
+
+    let mut outer = Sha1::new();
+    outer.update(&block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.update(&inner.digest().bytes());
+    outer.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TempDir;
+
+    const KNOWN_HOSTS: &str = "\
+# comment
+bastion.example.com,10.0.0.1 ssh-ed25519 AAAA1
+bastion.example.com ssh-rsa AAAA2
+|1|AAECAwQFBgcICQoLDA0ODxAREhM=|5cgNeu1GkzQnF+TOvboia8+Tkxo= ssh-ed25519 AAAA3
+|1|AAECAwQFBgcICQoLDA0ODxAREhM=|iB8ji+G7JiWchxZqOjryGAnIiTo= ssh-ed25519 AAAA4
+*.internal,!secret.internal ssh-ed25519 AAAA5
+app.internal ssh-ed25519 AAAA6
+@cert-authority *.corp ssh-ed25519 AAAA7
+@revoked * ssh-rsa AAAA8
+old.example.com ssh-rsa AAAA8
+malformed
+";
+
+    #[test]
+    fn test_status() {
+        let entries = parse(KNOWN_HOSTS, "known_hosts");
+        assert_eq!(entries.len(), 9);
+
+        let status = |host, port| status(&entries, host, port);
+        assert_eq!(status("Bastion.example.com", 22), HostStatus::Known(vec!["ssh-ed25519".into(), "ssh-rsa".into()]));
+        assert_eq!(status("bastion.example.com", 2222), HostStatus::Unknown);
+        assert_eq!(status("db.example.com", 22), HostStatus::Known(vec!["ssh-ed25519".into()]));
+        assert_eq!(status("git.example.com", 2222), HostStatus::Known(vec!["ssh-ed25519".into()]));
+        assert_eq!(status("git.example.com", 22), HostStatus::Unknown);
+        assert_eq!(status("web.internal", 22), HostStatus::Known(vec!["ssh-ed25519".into()]));
+        assert_eq!(status("secret.internal", 22), HostStatus::Unknown);
+        assert_eq!(status("app.internal", 22), HostStatus::Conflicting {
+            key_type: "ssh-ed25519".into(),
+            locations: vec!["known_hosts:6".into(), "known_hosts:7".into()],
+        });
+        assert_eq!(status("gw.corp", 22), HostStatus::KnownByAuthority);
+        assert_eq!(status("old.example.com", 22), HostStatus::Revoked {
+            key_type: "ssh-rsa".into(),
+            location: "known_hosts:9".into(),
+        });
+    }
+
+    #[test]
+    fn test_resolve() {
+        // A fake ssh which prints a part of the `ssh -G` output, as configured with HostName and Port
+        let dir = TempDir::new("known-hosts");
+        let ssh = dir.script("ssh", "test \"$1 $2 $3 $4\" = '-G -p 2200 db' || exit 1\n\
+                                     printf 'user me\\nhostname db.example.com\\nport 2222\\n'\n\
+                                     printf 'userknownhostsfile /tmp/hosts /tmp/hosts2\\n'\n");
+        let jump = SingleJump { port: Some(2200), ssh_path: Some(ssh.display().to_string()), ..SingleJump::new("db".into()) };
+        assert_eq!(resolve(&jump).unwrap(), Target {
+            host: "db.example.com".into(),
+            port: 2222,
+            files: vec!["/tmp/hosts".into(), "/tmp/hosts2".into()],
+        });
+
+        let jump = SingleJump { ssh_path: Some(ssh.display().to_string()), ..SingleJump::new("db".into()) };
+        assert!(resolve(&jump).is_err());
+
+        let target = parse_target("hostname 10.0.0.5\nport 2222\nhostkeyalias db\nglobalknownhostsfile /etc/hosts.keys\n", "db");
+        assert_eq!(target, Target { host: "db".into(), port: 22, files: vec!["/etc/hosts.keys".into()] });
+
+        assert_eq!(parse_target("", "db"), Target { host: "db".into(), port: 22, files: default_files() });
+    }
+}
@@ -0,0 +1,35 @@
+use std::ffi::OsString;
+
+use itertools::Itertools;
+use exec;
+
+use config::ConfigDefinition;
+use execution::Execution;
+use keys;
+use mux;
+
+/// Prepares connecting to the profile and returns its command, which is printed.
+///
+/// Problems with local keys are only reported, like ssh does.
+pub fn prepare(definition: ConfigDefinition) -> Vec<OsString> {
+    for warning in keys::warnings(&keys::check_chain(&definition.chain)) {
+        eprintln!("{}", warning);
+    }
+    if definition.multiplex {
+        mux::ensure_control_dir();
+    }
+
+    let args = Execution::from(definition).args();
+    println!("{}", args.iter().map(|arg| arg.to_string_lossy()).join(" "));
+    args
+}
+
+/// Replaces sshc with the command of the profile.
+pub fn run(definition: ConfigDefinition) -> ! {
+    let args = prepare(definition);
+
+    let error = exec::execvp(&args[0], &args);
+
+    eprintln!("Failed to run SSH: {}", error);
+    ::std::process::exit(1);
+}
@@ -0,0 +1,26 @@
+//! Configuration parsing and command rendering of sshc.
+//!
+//! The stable API consists of the `config` module, which loads profiles and looks them up by
+//! their dotted paths, the `serialize` module, which writes them back to TOML, and the
+//! `execution` module, which normalizes profiles and renders them into SSH command lines.
+
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate serde_derive;
+extern crate toml;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate serde;
+extern crate itertools;
+extern crate sha1;
+
+#[cfg(test)] extern crate quickcheck;
+
+pub mod config;
+pub mod execution;
+pub mod serialize;
+
+mod value;
+mod inventory;
+mod dirs;
+
+#[cfg(test)] mod testing;
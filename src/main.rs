@@ -1,25 +1,47 @@
-#[macro_use] extern crate error_chain;
-#[macro_use] extern crate serde_derive;
 #[macro_use(crate_version, crate_authors)] extern crate clap;
-extern crate toml;
-extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate toml_edit;
+extern crate serde_json;
 extern crate shellexpand;
 extern crate exec;
 extern crate cursive;
 extern crate either;
 extern crate itertools;
+extern crate glob;
+extern crate sha1;
+extern crate base64;
+extern crate libc;
+extern crate sshc;
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use clap::{App, Arg, AppSettings, SubCommand, ArgMatches};
 
-use clap::{App, Arg, AppSettings};
+use sshc::{config, execution};
+use sshc::execution::Execution;
 
-use config::ConfigItem;
-use execution::Execution;
+use transfer::{Location, Tool};
 
-mod config;
 mod ui;
-mod execution;
+mod dirs;
+mod preview;
+mod transfer;
+mod edit;
+mod batch;
+mod history;
+mod known_hosts;
+mod keys;
+mod record;
+mod mux;
+mod pins;
+mod launch;
+mod state_files;
+
+#[cfg(test)] mod testing;
 
 fn main() {
     let matches = App::new("sshc")
@@ -33,7 +55,84 @@ fn main() {
         )
         .args_from_usage(
             "-p, --profile=[PROFILE] 'Run the specified profile immediately'
-             -d, --dry-run 'Just print the command'"
+             -d, --dry-run 'Just print the command'
+             -y, --yes 'Do not ask for confirmation of dangerous profiles'"
+        )
+        .arg(
+            Arg::from_usage("--record 'Record the session to a file, as if the profile had record = true'")
+                .requires("profile")
+        )
+        .arg(set_arg().requires("profile"))
+        .subcommand(
+            SubCommand::with_name("exec")
+                .about("Runs a command on all profiles of a group or matching a pattern")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::from_usage("-j, --jobs=[N] 'Maximum number of simultaneous connections'")
+                        .default_value("8")
+                )
+                .args_from_usage(
+                    "-y, --yes 'Do not ask for confirmation of dangerous profiles'
+                     <TARGET> 'Profile, group or a pattern like prod.*.web'
+                     <COMMAND>... 'Command to run, joined with spaces into one shell command like with ssh'"
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .about("Copies files to or from the last host of a profile")
+                .args_from_usage(
+                    "-d, --dry-run 'Just print the command'
+                     -y, --yes 'Do not ask for confirmation of dangerous profiles'
+                     -r, --recursive 'Copy directories recursively'
+                     --rsync 'Use rsync instead of scp'
+                     <SOURCE> 'Source path, either local or profile:path'
+                     <DESTINATION> 'Destination path, either local or profile:path'"
+                )
+                .arg(set_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("mux")
+                .about("Controls shared connections of multiplexed profiles")
+                .arg(
+                    Arg::from_usage("<ACTION> 'Check the shared connection or stop it, or list all running ones'")
+                        .possible_values(&["status", "stop", "list"])
+                )
+                .arg(Arg::from_usage("[PROFILE] 'Profile name, required for status and stop'"))
+                .arg(set_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists profiles with their descriptions")
+                .arg(
+                    Arg::from_usage("-t, --tag=[TAG] 'Show only profiles with this tag; can be repeated'")
+                        .multiple(true)
+                        .number_of_values(1)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Explains how the jumps of a profile are filled in before connecting")
+                .arg(Arg::from_usage("<PROFILE> 'Profile name'"))
+                .arg(set_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks a profile for problems which would interrupt connecting")
+                .args_from_usage(
+                    "--hosts 'Check that the host key of every jump is known and unambiguous'
+                     --keys 'Check that the key files of the jumps exist, are private and can be used without a passphrase'
+                     --add-keys 'Load keys which are not in the agent with ssh-add; implies --keys'
+                     <PROFILE> 'Profile name'"
+                )
+                .arg(set_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Shows recently launched profiles")
+                .arg(
+                    Arg::from_usage("-n, --count=[N] 'Number of entries to show'")
+                        .default_value("20")
+                )
         )
         .get_matches();
 
@@ -48,47 +147,367 @@ fn main() {
         }
     };
 
+    match matches.subcommand() {
+        ("exec", Some(matches)) => run_exec(config, matches),
+        ("cp", Some(matches)) => run_cp(config, matches),
+        ("mux", Some(matches)) => run_mux(config, matches),
+        ("list", Some(matches)) => run_list(config, matches),
+        ("explain", Some(matches)) => run_explain(config, matches),
+        ("check", Some(matches)) => run_check(config, matches),
+        ("history", Some(matches)) => run_history(matches),
+        _ => {}
+    }
+
     let dry_run = matches.is_present("dry-run");
 
     if let Some(profile) = matches.value_of("profile") {
-        let parts: Vec<_> = profile.split(".").collect();
-
-        let mut group = config.root;
-        let mut definition = None;
-        for (i, part) in parts.iter().cloned().enumerate() {
-            match group.definitions.remove(part) {
-                Some(ConfigItem::Definition(d)) =>
-                    if i == parts.len() - 1 {
-                        definition = Some(d);
-                    } else {
-                        break;
-                    },
-                Some(ConfigItem::Subgroup(g)) =>
-                    if i < parts.len() - 1 {
-                        group = g;
-                    } else {
-                        break;
-                    },
-                _ => {}
+        let mut definition = find_definition(&config, profile, &matches);
+        definition.record |= matches.is_present("record");
+
+        if !dry_run && !matches.is_present("yes") && definition.requires_confirmation() {
+            confirm(profile, &definition);
+        }
+
+        if !dry_run && definition.multiplex {
+            mux::register(profile);
+        }
+
+        // The exit code of a recorded session is known, so it is added to the history afterwards
+        if !dry_run && definition.record {
+            record::run_profile(profile, definition);
+        }
+
+        history::record(&history::Entry::new(profile.into(), dry_run, None));
+
+        if dry_run {
+            println!("{}", Execution::from(definition).command_line());
+        } else {
+            launch::run(definition);
+        }
+
+    } else {
+        ui::run(config, &config_path, dry_run);
+    }
+}
+
+/// Asks to type the profile name on the terminal, exiting unless it matches.
+fn confirm(profile: &str, definition: &config::ConfigDefinition) {
+    let env = definition.env.as_ref().map(|env| format!(" ({})", env)).unwrap_or_default();
+    eprint!("You are about to connect to {}{}. Type the profile name to continue: ", profile, env);
+
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) if answer.trim() == profile => {}
+        Ok(_) => {
+            eprintln!("Aborted; use --yes to skip the confirmation");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to read the confirmation: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn set_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("set")
+        .long("set")
+        .value_name("NAME=VALUE")
+        .help("Set a parameter of the profile")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+}
+
+/// Fills the profile parameters from `--set` arguments, exiting on errors.
+fn instantiate(profile: &str, definition: &config::ConfigDefinition, matches: &ArgMatches) -> config::ConfigDefinition {
+    let mut params = BTreeMap::new();
+    for assignment in matches.values_of("set").into_iter().flat_map(|v| v) {
+        let mut parts = assignment.splitn(2, '=');
+        match (parts.next().unwrap(), parts.next()) {
+            (name, Some(value)) => { params.insert(name.to_owned(), value.to_owned()); }
+            (_, None) => {
+                eprintln!("Invalid parameter assignment, expected NAME=VALUE: {}", assignment);
+                std::process::exit(1);
             }
         }
+    }
+
+    match definition.instantiate(&params) {
+        Ok(definition) => definition,
+        Err(e) => {
+            eprintln!("Invalid parameters for profile {}: {}", profile, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Finds the profile and fills its parameters, exiting on errors.
+fn find_definition(config: &config::Config, profile: &str, matches: &ArgMatches) -> config::ConfigDefinition {
+    match config.root.get_definition(profile) {
+        Ok(definition) => instantiate(profile, definition, matches),
+        Err(e) => {
+            eprintln!("Invalid profile name {}: {}", profile, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_mux(config: config::Config, matches: &ArgMatches) -> ! {
+    let request = match matches.value_of("ACTION").unwrap() {
+        "status" => "check",
+        "stop" => "exit",
+        _ => run_mux_list(&config),
+    };
+    let profile = match matches.value_of("PROFILE") {
+        Some(profile) => profile,
+        None => {
+            eprintln!("A profile is required for mux {}", matches.value_of("ACTION").unwrap());
+            std::process::exit(1);
+        }
+    };
+
+    let definition = find_definition(&config, profile, matches);
+    let command = match Execution::from(definition).control_command(request) {
+        Some(command) => command,
+        None => {
+            eprintln!("Profile {} is not multiplexed", profile);
+            std::process::exit(1);
+        }
+    };
+
+    match std::process::Command::new(&command[0]).args(&command[1..]).status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("Failed to run {}: {}", command[0], e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the profiles whose master connections started by sshc are still running, forgetting the others.
+fn run_mux_list(config: &config::Config) -> ! {
+    let profiles = match mux::load() {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            eprintln!("Failed to load master connections: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Parameters are filled with their defaults, and profiles which are gone cannot be checked
+    let is_running = |profile: &str| config.root.get_definition(profile).ok()
+        .and_then(|definition| definition.instantiate(&BTreeMap::new()).ok())
+        .and_then(|definition| Execution::from(definition).control_command("check"))
+        .and_then(|command| std::process::Command::new(&command[0]).args(&command[1..])
+            .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+            .status().ok())
+        .map(|status| status.success())
+        .unwrap_or(false);
 
-        if let Some(definition) = definition {
-            let mut e = Execution::from(definition);
-            if dry_run {
-                println!("{}", e.command_line());
-            } else {
-                e.run();
+    let running: Vec<_> = profiles.into_iter().filter(|profile| is_running(profile)).collect();
+    for profile in &running {
+        println!("{}", profile);
+    }
+
+    if let Err(e) = mux::save(&running) {
+        eprintln!("Failed to save master connections: {}", e);
+    }
+    std::process::exit(0);
+}
+
+fn run_cp(config: config::Config, matches: &ArgMatches) -> ! {
+    let source = Location::parse(&config.root, matches.value_of("SOURCE").unwrap());
+    let destination = Location::parse(&config.root, matches.value_of("DESTINATION").unwrap());
+
+    let profile = match (&source, &destination) {
+        (&Location::Remote { ref profile, .. }, &Location::Local(_)) |
+        (&Location::Local(_), &Location::Remote { ref profile, .. }) => profile.clone(),
+        (&Location::Local(_), &Location::Local(_)) => {
+            eprintln!("Either source or destination should be a remote path like profile:path");
+            std::process::exit(1);
+        }
+        _ => {
+            eprintln!("Copying between two profiles is not supported");
+            std::process::exit(1);
+        }
+    };
+
+    let definition = find_definition(&config, &profile, matches);
+
+    if !matches.is_present("dry-run") && !matches.is_present("yes") && definition.requires_confirmation() {
+        confirm(&profile, &definition);
+    }
+
+    let tool = if matches.is_present("rsync") { Tool::Rsync } else { Tool::Scp };
+    let command = match transfer::command(&definition, &source, &destination, tool, matches.is_present("recursive")) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Cannot copy with {}: {}", profile, e);
+            std::process::exit(1);
+        }
+    };
+
+    if matches.is_present("dry-run") {
+        println!("{}", transfer::command_line(&command));
+        std::process::exit(0);
+    }
+
+    for warning in keys::warnings(&keys::check_direct(&definition.chain)) {
+        eprintln!("{}", warning);
+    }
+
+    let error = exec::execvp(&command[0], &command);
+    eprintln!("Failed to run {}: {}", command[0], error);
+    std::process::exit(1);
+}
+
+fn run_exec(config: config::Config, matches: &ArgMatches) -> ! {
+    let target = matches.value_of("TARGET").unwrap();
+    let command: Vec<_> = matches.values_of("COMMAND").unwrap().collect();
+
+    let jobs = match matches.value_of("jobs").unwrap().parse() {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("Invalid number of jobs: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let targets = match batch::select_targets(&config.root, target) {
+        Ok(targets) => targets,
+        Err(e) => {
+            eprintln!("Invalid target: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if !matches.is_present("yes") {
+        for &(ref profile, ref definition) in &targets {
+            if definition.requires_confirmation() {
+                confirm(profile, definition);
             }
+        }
+    }
 
-        } else {
-            eprintln!("Invalid profile name: {}", profile);
+    let success = batch::run(targets, &command.join(" "), jobs);
+    std::process::exit(if success { 0 } else { 1 });
+}
+
+fn run_list(config: config::Config, matches: &ArgMatches) -> ! {
+    let tags: Vec<_> = matches.values_of("tag").into_iter().flat_map(|v| v).collect();
+
+    let definitions: Vec<_> = config.root.iter_definitions().into_iter()
+        .filter(|&(_, definition)| tags.iter().all(|tag| definition.tags.iter().any(|t| t == *tag)))
+        .collect();
+
+    let width = definitions.iter().map(|&(ref path, _)| path.len()).max().unwrap_or(0);
+    for (path, definition) in definitions {
+        let mut details = Vec::new();
+        details.extend(definition.env.as_ref().map(|env| format!("[{}]", env)));
+        details.extend(definition.description.clone());
+        if !definition.tags.is_empty() {
+            details.push(format!("(tags: {})", definition.tags.join(", ")));
+        }
+        println!("{:width$}  {}", path, details.join("  "), width = width);
+    }
+    std::process::exit(0);
+}
+
+fn run_explain(config: config::Config, matches: &ArgMatches) -> ! {
+    let definition = find_definition(&config, matches.value_of("PROFILE").unwrap(), matches);
+    for line in preview::explain(&definition) {
+        println!("{}", line);
+    }
+    std::process::exit(0);
+}
+
+fn run_check(config: config::Config, matches: &ArgMatches) -> ! {
+    let definition = find_definition(&config, matches.value_of("PROFILE").unwrap(), matches);
+
+    // Without any flags, everything is checked
+    let all = !matches.is_present("hosts") && !matches.is_present("keys") && !matches.is_present("add-keys");
+    let mut success = true;
+
+    if all || matches.is_present("hosts") {
+        let proxied = config::proxied_jumps(&definition.chain);
+        for (idx, jump) in definition.chain.iter().enumerate() {
+            // Jumps after the first one run by the local ssh are connected to from the previous
+            // host, whose ssh_config and known_hosts files are out of reach
+            if idx > proxied {
+                let result = known_hosts::load_default()
+                    .map(|entries| known_hosts::status(&entries, &jump.host, jump.port.unwrap_or(22)));
+                match result {
+                    Ok(status) => println!(
+                        "Jump {}: {}: {}, checked locally only since it is connected to from jump {}",
+                        idx + 1, jump.host, status.describe(), idx
+                    ),
+                    Err(e) => println!("Jump {}: {}: failed to read known hosts: {}", idx + 1, jump.host, e),
+                }
+                continue;
+            }
+
+            let result = known_hosts::resolve(jump).and_then(|target| {
+                let entries = known_hosts::load_all(&target.files)?;
+                Ok((known_hosts::status(&entries, &target.host, target.port), target))
+            });
+            match result {
+                Ok((status, target)) => {
+                    let name = if target.host == jump.host { jump.host.clone() } else { format!("{} ({})", jump.host, target.host) };
+                    println!("Jump {}: {}: {}", idx + 1, name, status.describe());
+                    success &= status.is_ok();
+                }
+                Err(e) => {
+                    println!("Jump {}: {}: cannot be checked: {}", idx + 1, jump.host, e);
+                    success = false;
+                }
+            }
+        }
+    }
+
+    if all || matches.is_present("keys") || matches.is_present("add-keys") {
+        for check in keys::check_chain(&definition.chain) {
+            println!("{}", check.describe());
+
+            let add = match check.status {
+                keys::KeyStatus::Usable { in_agent: false, .. } => matches.is_present("add-keys"),
+                _ => false,
+            };
+            if add {
+                match keys::add_to_agent(&check.path) {
+                    Ok(()) => continue,
+                    Err(e) => eprintln!("Failed to load {}: {}", check.path, e),
+                }
+            }
+            success &= check.status.is_ok();
+        }
+    }
+
+    std::process::exit(if success { 0 } else { 1 });
+}
+
+fn run_history(matches: &ArgMatches) -> ! {
+    let count = match matches.value_of("count").unwrap().parse() {
+        Ok(count) => count,
+        Err(e) => {
+            eprintln!("Invalid number of entries: {}", e);
             std::process::exit(1);
         }
+    };
 
-    } else {
-        ui::run(config, dry_run);
+    let entries = match history::load() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to load history: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let skip = entries.len().saturating_sub(count);
+    for entry in &entries[skip..] {
+        println!("{}", entry.describe());
     }
+    std::process::exit(0);
 }
 
 #[inline]
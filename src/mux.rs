@@ -0,0 +1,71 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use libc;
+
+use dirs;
+use execution;
+use state_files;
+
+/// The file listing profiles whose master connections were started by sshc.
+///
+/// Control sockets are named by ssh after a hash of the connection, which cannot be mapped back
+/// to profiles, so the profiles are recorded next to them instead.
+fn masters_file() -> PathBuf {
+    dirs::runtime_dir().join("masters")
+}
+
+/// Loads the profiles which may have a running master connection, one full path per line.
+pub fn load() -> io::Result<Vec<String>> {
+    state_files::load_lines(&masters_file())
+}
+
+pub fn save(profiles: &[String]) -> io::Result<()> {
+    ensure_private_dir(&dirs::runtime_dir())?;
+    state_files::save_lines(&masters_file(), profiles)
+}
+
+/// Records that a multiplexed profile is being connected, so that `sshc mux list` shows it.
+pub fn register(profile: &str) {
+    let result = load().and_then(|mut profiles| {
+        if profiles.iter().any(|p| p == profile) {
+            return Ok(());
+        }
+        profiles.push(profile.into());
+        save(&profiles)
+    });
+    state_files::report(result, "the master connection", &masters_file());
+}
+
+/// Makes sure that the control sockets are out of reach of other users, exiting otherwise.
+pub fn ensure_control_dir() {
+    let result = ensure_private_dir(&dirs::runtime_dir())
+        .and_then(|_| ensure_private_dir(&execution::control_dir()));
+    if let Err(e) = result {
+        eprintln!("Cannot share the connection: {}", e);
+        ::std::process::exit(1);
+    }
+}
+
+/// Creates the directory like `dirs::ensure_dir`, then refuses it unless it is owned by the user
+/// and accessible only to them.
+///
+/// The mode is only applied to directories which are created, while the fallback runtime
+/// directory in `/tmp` has a predictable name; another user could create it in advance and take
+/// over the sockets inside.
+fn ensure_private_dir(path: &Path) -> io::Result<()> {
+    dirs::ensure_dir(path)?;
+
+    let metadata = fs::symlink_metadata(path)?;
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} must be a directory owned by the current user with mode 0700", path.display()),
+        ));
+    }
+
+    Ok(())
+}
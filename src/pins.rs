@@ -0,0 +1,67 @@
+use std::io;
+use std::path::PathBuf;
+
+use dirs;
+use state_files;
+
+fn pins_file() -> PathBuf {
+    dirs::state_dir().join("pinned")
+}
+
+/// Loads the pinned profiles in the order they are shown, one full path per line.
+pub fn load() -> io::Result<Vec<String>> {
+    state_files::load_lines(&pins_file())
+}
+
+pub fn save(pins: &[String]) -> io::Result<()> {
+    dirs::ensure_dir(&dirs::state_dir())?;
+    state_files::save_lines(&pins_file(), pins)
+}
+
+/// Pins the profile if it is not pinned and unpins it otherwise.
+///
+/// Returns `true` if the profile is pinned now.
+pub fn toggle(pins: &mut Vec<String>, profile: &str) -> bool {
+    match pins.iter().position(|p| p == profile) {
+        Some(idx) => {
+            pins.remove(idx);
+            false
+        }
+        None => {
+            pins.push(profile.into());
+            true
+        }
+    }
+}
+
+/// Moves the pinned profile one position up or down, if possible.
+pub fn shift(pins: &mut Vec<String>, profile: &str, up: bool) {
+    if let Some(idx) = pins.iter().position(|p| p == profile) {
+        if up && idx > 0 {
+            pins.swap(idx, idx - 1);
+        } else if !up && idx + 1 < pins.len() {
+            pins.swap(idx, idx + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle_and_shift() {
+        let mut pins = vec!["a".to_owned(), "b.c".into()];
+
+        assert!(toggle(&mut pins, "d"));
+        assert!(!toggle(&mut pins, "a"));
+        assert_eq!(pins, vec!["b.c".to_owned(), "d".into()]);
+
+        shift(&mut pins, "d", true);
+        assert_eq!(pins, vec!["d".to_owned(), "b.c".into()]);
+        shift(&mut pins, "d", true);
+        shift(&mut pins, "b.c", false);
+        shift(&mut pins, "x", false);
+        assert_eq!(pins, vec!["d".to_owned(), "b.c".into()]);
+    }
+}
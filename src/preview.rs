@@ -0,0 +1,191 @@
+use itertools::Itertools;
+
+use config::{proxied_jumps, ConfigDefinition, State};
+use execution::{forward_spec, normalize_definition_explained, Derivation};
+
+/// Where the tunnel of a jump came from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Origin {
+    /// Set in the config
+    Explicit,
+    /// Propagated from the tunnel of the given jump
+    Propagated(usize),
+}
+
+/// Returns the table of jumps of the definition with the values used for the connection.
+///
+/// Values which are not written in the config but added by normalization are marked.
+pub fn hops_table(definition: &ConfigDefinition) -> Vec<String> {
+    let (normalized, derivations) = normalize_definition_explained(definition.clone());
+    let origins = tunnel_origins(&normalized, &derivations);
+    let proxied = proxied_jumps(&normalized.chain);
+
+    let flag = |state: &State<()>, derived: &[Derivation], derivation: Derivation| match *state {
+        State::Enabled(()) if derived.contains(&derivation) => "yes (auto)".to_owned(),
+        State::Enabled(()) => "yes".into(),
+        _ => "no".into(),
+    };
+
+    let mut rows = vec![
+        ["#", "host", "port", "user", "key", "-A", "-N", "tunnel"].iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+    ];
+    for (i, jump) in normalized.chain.iter().enumerate() {
+        let tunnel = match (&jump.tunnel, origins[i]) {
+            (&State::Enabled(ref tunnel), Some(Origin::Propagated(from))) =>
+                format!("{} (from {})", forward_spec(tunnel), from + 1),
+            (&State::Enabled(ref tunnel), _) => forward_spec(tunnel),
+            _ => "-".into(),
+        };
+
+        rows.push(vec![
+            (i + 1).to_string(),
+            jump.host.clone(),
+            jump.port.map(|p| p.to_string()).unwrap_or_else(|| "-".into()),
+            jump.user.clone().unwrap_or_else(|| "-".into()),
+            match jump.key {
+                // Keys of nested jumps are read on the previous host
+                Some(ref key) if i > proxied => format!("{} (on {})", key, normalized.chain[i - 1].host),
+                Some(ref key) if i > 0 => format!("{} (local)", key),
+                Some(ref key) => key.clone(),
+                None => "-".into(),
+            },
+            flag(&jump.agent_passthrough, &derivations[i], Derivation::AgentPassthroughAdded),
+            flag(&jump.no_command, &derivations[i], Derivation::NoCommandAdded),
+            tunnel,
+        ]);
+    }
+
+    let widths: Vec<_> = (0..rows[0].len())
+        .map(|col| rows.iter().map(|row| row[col].chars().count()).max().unwrap_or(0))
+        .collect();
+    rows.iter()
+        .map(|row| row.iter().zip(&widths).map(|(cell, &width)| format!("{:width$}", cell, width = width)).join("  "))
+        .map(|line| line.trim_right().to_owned())
+        .collect()
+}
+
+/// Describes the path of every tunnel of the definition, from the listening port to the final destination.
+pub fn tunnel_paths(definition: &ConfigDefinition) -> Vec<String> {
+    let (normalized, derivations) = normalize_definition_explained(definition.clone());
+    let origins = tunnel_origins(&normalized, &derivations);
+    let chain = &normalized.chain;
+
+    let mut paths = Vec::new();
+    for (start, jump) in chain.iter().enumerate() {
+        let tunnel = match (&jump.tunnel, origins[start]) {
+            (&State::Enabled(ref tunnel), Some(Origin::Explicit)) => tunnel,
+            _ => continue,
+        };
+
+        // The tunnel goes through all jumps it was propagated to
+        let end = (start + 1..chain.len())
+            .take_while(|&i| origins[i] == Some(Origin::Propagated(start)))
+            .last()
+            .unwrap_or(start);
+
+        let listener = format!(
+            "{}:{}",
+            tunnel.local_host.as_ref().map(|h| h.as_str()).unwrap_or("localhost"),
+            tunnel.local_port.map(|p| p.to_string()).unwrap_or_default()
+        );
+        let listener = match start {
+            0 => listener,
+            _ => format!("{} on {}", listener, chain[start - 1].host),
+        };
+        let destination = format!(
+            "{}:{} as seen from {}",
+            tunnel.remote_host.as_ref().map(|h| h.as_str()).unwrap_or("localhost"),
+            tunnel.remote_port.map(|p| p.to_string()).unwrap_or_default(),
+            chain[end].host
+        );
+
+        let mut path = vec![listener];
+        path.extend(chain[start..end + 1].iter().map(|j| j.host.clone()));
+        path.push(destination);
+        paths.push(path.join(" -> "));
+    }
+    paths
+}
+
+/// Finds out for each jump whether its tunnel is set in the config or propagated from a previous jump.
+fn tunnel_origins(normalized: &ConfigDefinition, derivations: &[Vec<Derivation>]) -> Vec<Option<Origin>> {
+    let mut origins: Vec<Option<Origin>> = Vec::new();
+    for (jump, derived) in normalized.chain.iter().zip(derivations) {
+        let inherited = derived.iter().filter_map(|d| match *d {
+            Derivation::TunnelInherited(from) => Some(from),
+            _ => None,
+        }).next();
+
+        let origin = match (&jump.tunnel, inherited) {
+            (_, Some(from)) => match origins[from] {
+                Some(Origin::Propagated(start)) => Some(Origin::Propagated(start)),
+                _ => Some(Origin::Propagated(from)),
+            },
+            (&State::Enabled(_), None) => Some(Origin::Explicit),
+            _ => None,
+        };
+        origins.push(origin);
+    }
+    origins
+}
+
+/// Explains every value derived by normalization, jump by jump.
+pub fn explain(definition: &ConfigDefinition) -> Vec<String> {
+    let (normalized, derivations) = normalize_definition_explained(definition.clone());
+
+    let mut lines = Vec::new();
+    for (i, (jump, derived)) in normalized.chain.iter().zip(&derivations).enumerate() {
+        lines.push(format!("Jump {}: {}", i + 1, jump.destination()));
+        if derived.is_empty() {
+            lines.push("  nothing derived, the jump is used as configured".into());
+        }
+        lines.extend(derived.iter().map(|d| format!("  {}", d.describe())));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{SingleJump, Tunnel};
+
+    fn jump(host: &str) -> SingleJump {
+        SingleJump::new(host.into())
+    }
+
+    #[test]
+    fn test_preview() {
+        let tunnel = Tunnel { local_port: Some(9091), local_host: None, remote_port: None, remote_host: None };
+        let definition = ConfigDefinition {
+            chain: vec![
+                SingleJump { user: Some("user".into()), ..jump("public-vpn.cc") },
+                SingleJump { port: Some(2222), tunnel: State::Enabled(tunnel), ..jump("gateway") },
+                jump("home-server.vpn"),
+            ],
+            ..ConfigDefinition::default()
+        };
+
+        assert_eq!(hops_table(&definition), vec![
+            "#  host             port  user  key  -A          -N          tunnel",
+            "1  public-vpn.cc    -     user  -    no          no          -",
+            "2  gateway          2222  -     -    yes (auto)  no          9091:localhost:9091",
+            "3  home-server.vpn  -     -     -    no          yes (auto)  9091:localhost:9091 (from 2)",
+        ]);
+
+        assert_eq!(tunnel_paths(&definition), vec![
+            "localhost:9091 on public-vpn.cc -> gateway -> home-server.vpn -> localhost:9091 as seen from home-server.vpn",
+        ]);
+
+        assert_eq!(explain(&definition), vec![
+            "Jump 1: user@public-vpn.cc",
+            "  nothing derived, the jump is used as configured",
+            "Jump 2: gateway",
+            "  tunnel remote host set to localhost because it is not specified",
+            "  tunnel remote port set to 9091 because only the local port is specified",
+            "  -A added because the jump has a tunnel and is not the last one",
+            "Jump 3: home-server.vpn",
+            "  tunnel inherited from jump 2 because this jump does not disable it",
+            "  -N added because the last jump has a tunnel",
+        ]);
+    }
+}
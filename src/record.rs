@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::str;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc;
+use serde_json;
+use shellexpand;
+
+use config::ConfigDefinition;
+use dirs;
+use history;
+use launch;
+
+/// Directory for recordings of profiles which do not set `record_dir`.
+///
+/// This is `$XDG_STATE_HOME/sshc/recordings`, falling back to `~/.local/state/sshc/recordings`.
+pub fn default_dir() -> PathBuf {
+    dirs::state_dir().join("recordings")
+}
+
+/// Connects to the profile with the session recorded, adds the exit code to the history and exits with it.
+pub fn run_profile(profile: &str, definition: ConfigDefinition) -> ! {
+    let dir = match definition.record_dir {
+        Some(ref dir) => PathBuf::from(shellexpand::tilde(dir).into_owned()),
+        None => default_dir(),
+    };
+
+    let args = launch::prepare(definition);
+
+    let result = create_cast(&dir, profile, now()).and_then(|(path, cast)| {
+        eprintln!("Recording the session to {}", path.display());
+        // Without a terminal there is nothing to switch to raw mode, and no keys to pass through
+        let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+        run(&args, cast, interactive)
+    });
+
+    match result {
+        Ok(code) => {
+            history::record(&history::Entry::new(profile.into(), false, Some(code)));
+            ::std::process::exit(code);
+        }
+        Err(e) => {
+            eprintln!("Failed to record the session in {}: {}", dir.display(), e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// Names the recording after the profile and the time, like `prod.db_2017-07-14_02-42-00.cast`.
+///
+/// Attempts after the first one are numbered, like `prod.db_2017-07-14_02-42-00_2.cast`.
+fn file_name(profile: &str, timestamp: u64, attempt: u32) -> String {
+    let time = history::format_timestamp(timestamp).replace(' ', "_").replace(':', "-");
+    match attempt {
+        1 => format!("{}_{}.cast", profile.replace('/', "_"), time),
+        n => format!("{}_{}_{}.cast", profile.replace('/', "_"), time, n),
+    }
+}
+
+/// Creates a new recording in `dir`, readable only by the user.
+///
+/// An existing file is never overwritten; if the profile was already recorded in the same second,
+/// the next free number is added to the name.
+fn create_cast(dir: &Path, profile: &str, timestamp: u64) -> io::Result<(PathBuf, File)> {
+    dirs::ensure_dir(dir)?;
+
+    let mut attempt = 1;
+    loop {
+        let path = dir.join(file_name(profile, timestamp, attempt));
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The first line of an asciinema v2 cast file.
+#[derive(Serialize)]
+struct Header {
+    version: u32,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    env: BTreeMap<&'static str, String>,
+}
+
+/// Runs the command under a pseudo-terminal owned by sshc, passing the output through and writing
+/// it to `cast` in the asciinema v2 format. Returns the exit code of the command.
+///
+/// If `interactive` is set, the terminal of sshc is switched to raw mode and the input is passed
+/// to the command as well. The terminal size is taken once at the start, so resizing the window
+/// does not reach the command.
+fn run(args: &[OsString], cast: File, interactive: bool) -> io::Result<i32> {
+    let mut size = terminal_size();
+    let (mut master, slave) = open_pty(&mut size)?;
+
+    let mut cast = BufWriter::new(cast);
+    let header = Header {
+        version: 2,
+        width: size.ws_col,
+        height: size.ws_row,
+        timestamp: now(),
+        env: ["TERM", "SHELL"].iter().filter_map(|&name| env::var(name).ok().map(|value| (name, value))).collect(),
+    };
+    writeln!(cast, "{}", serde_json::to_string(&header).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?)?;
+
+    let mut command = Command::new(&args[0]);
+    command.args(&args[1..])
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    unsafe {
+        // Make the pseudo-terminal the controlling terminal of a new session, so that ssh can
+        // read passwords from it and Ctrl+C reaches the remote side
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // The copies of the slave side must be closed, otherwise reading the master never ends
+    drop(command);
+
+    // Input is not recorded, so it is simply copied; the pump is stopped before raw mode is left
+    let _raw_mode = if interactive { RawMode::enable() } else { None };
+    let _pump = if interactive { Some(InputPump::start(master.try_clone()?)?) } else { None };
+
+    let start = Instant::now();
+    let stdout = io::stdout();
+    let mut pending = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = match master.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Linux reports EIO once the command and its children close the terminal
+            Err(_) => break,
+        };
+
+        let mut out = stdout.lock();
+        out.write_all(&buf[..n])?;
+        out.flush()?;
+
+        pending.extend_from_slice(&buf[..n]);
+        let text = take_utf8(&mut pending);
+        if !text.is_empty() {
+            let event = (seconds(start.elapsed()), "o", text);
+            writeln!(cast, "{}", serde_json::to_string(&event).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?)?;
+        }
+    }
+    cast.flush()?;
+
+    let status = child.wait()?;
+    Ok(status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
+
+/// Returns the longest prefix of `pending` which is valid UTF-8, keeping an incomplete trailing
+/// sequence for the next read. Invalid bytes are replaced.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let valid = match str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(ref e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..valid]).into_owned();
+    pending.drain(..valid);
+    text
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Returns the size of the terminal of sshc, or 80x24 if the output is not a terminal.
+fn terminal_size() -> libc::winsize {
+    let mut size = libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    let found = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if !found || size.ws_col == 0 || size.ws_row == 0 {
+        size.ws_col = 80;
+        size.ws_row = 24;
+    }
+    size
+}
+
+fn open_pty(size: &mut libc::winsize) -> io::Result<(File, File)> {
+    let (mut master, mut slave) = (0, 0);
+    if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The command gets the slave side as its standard streams and nothing else
+    for &fd in &[master, slave] {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    unsafe { Ok((File::from_raw_fd(master), File::from_raw_fd(slave))) }
+}
+
+/// Copies the input of sshc to the command on a thread, which is stopped and joined when the
+/// value is dropped.
+struct InputPump {
+    /// Closing the write end of the pipe wakes the thread up
+    stop: Option<File>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InputPump {
+    fn start(mut input: File) -> io::Result<InputPump> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (stop_reader, stop_writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let thread = thread::spawn(move || {
+            let mut fds = [
+                libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: stop_reader.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            ];
+            let mut buf = [0u8; 1024];
+            loop {
+                if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    break;
+                }
+                if fds[1].revents != 0 {
+                    break;
+                }
+                if fds[0].revents != 0 {
+                    // The stdin of std is buffered, which would hide pending input from poll
+                    let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                    if n <= 0 || input.write_all(&buf[..n as usize]).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(InputPump { stop: Some(stop_writer), thread: Some(thread) })
+    }
+}
+
+impl Drop for InputPump {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Passes every key to the command instead of interpreting it, while the value is alive.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> Option<RawMode> {
+        let stdin = io::stdin();
+        let fd = stdin.as_raw_fd();
+        unsafe {
+            if libc::isatty(fd) == 0 {
+                return None;
+            }
+            let mut original = ::std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut original) < 0 {
+                return None;
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(fd, libc::TCSANOW, &raw);
+            Some(RawMode(original))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use config::SingleJump;
+    use execution::Execution;
+    use testing::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_create_cast() {
+        assert_eq!(file_name("prod.db", 1500000120, 1), "prod.db_2017-07-14_02-42-00.cast");
+
+        let dir = TempDir::new("record-cast");
+        let recordings = dir.join("recordings");
+        let (first, _) = create_cast(&recordings, "prod.db", 1500000120).unwrap();
+        let (second, _) = create_cast(&recordings, "prod.db", 1500000120).unwrap();
+        assert_eq!(first, recordings.join("prod.db_2017-07-14_02-42-00.cast"));
+        assert_eq!(second, recordings.join("prod.db_2017-07-14_02-42-00_2.cast"));
+        assert_eq!(fs::metadata(&second).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_run() {
+        // A fake ssh which checks that it runs in a terminal
+        let dir = TempDir::new("record");
+        let ssh = dir.script("ssh", "test -t 0 && echo \"connected to $1\"\nprintf 'caf\\303\\251\\n'\nexit 3\n");
+
+        let definition = ConfigDefinition {
+            chain: vec![SingleJump { ssh_path: Some(ssh.display().to_string()), ..SingleJump::new("db".into()) }],
+            ..ConfigDefinition::default()
+        };
+        let cast = dir.join("session.cast");
+        let file = File::create(&cast).unwrap();
+        // The terminal running the tests is left alone
+        assert_eq!(run(&Execution::from(definition).args(), file, false).unwrap(), 3);
+
+        let cast = fs::read_to_string(&cast).unwrap();
+        let mut lines = cast.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap());
+        let header = lines.next().unwrap();
+        assert_eq!(header["version"], 2);
+        assert!(header["width"].as_u64().unwrap() > 0);
+
+        let output: String = lines
+            .inspect(|event| assert_eq!(event[1], "o"))
+            .map(|event| event[2].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(output, "connected to db\r\ncafé\r\n");
+    }
+}
@@ -0,0 +1,439 @@
+use std::fmt::Write;
+
+use config::{self, Config, ConfigDefinition, ConfigGroup, ConfigItem, Param, Result, SingleJump, State, Tunnel};
+
+/// Renders the config as TOML which loads back into an equal config.
+///
+/// Every item is written in its most compact form: profiles are arrays unless they have
+/// profile-wide options, jumps are `user@host:port:key` strings unless they have other options,
+/// and tunnels are plain port numbers when they forward a port to the same port on localhost.
+///
+/// Fails if a profile or group has a name which the loader reserves, like `chain`.
+pub fn to_string(config: &Config) -> Result<String> {
+    let mut out = String::new();
+    write_group(&mut out, &[], &config.root)?;
+    Ok(out)
+}
+
+fn write_group(out: &mut String, path: &[&str], group: &ConfigGroup) -> Result<()> {
+    let mut arrays = Vec::new();
+    let mut tables = Vec::new();
+    let mut subgroups = Vec::new();
+    for (name, item) in &group.definitions {
+        config::check_name(&path.join("."), name)?;
+        match *item {
+            ConfigItem::Definition(ref definition) if is_plain(definition) => arrays.push((name, definition)),
+            ConfigItem::Definition(ref definition) => tables.push((name, definition)),
+            ConfigItem::Subgroup(ref subgroup) => subgroups.push((name, subgroup)),
+        }
+    }
+
+    // Groups without plain profiles are created implicitly by the sections of their children
+    if !path.is_empty() && (!arrays.is_empty() || group.definitions.is_empty()) {
+        write_header(out, path);
+    }
+    for (name, definition) in arrays {
+        writeln!(out, "{} = {}", key(name), chain(&definition.chain)).unwrap();
+    }
+
+    for (name, definition) in tables {
+        write_header(out, &child_path(path, name));
+        write_definition(out, definition);
+    }
+
+    for (name, subgroup) in subgroups {
+        write_group(out, &child_path(path, name), subgroup)?;
+    }
+
+    Ok(())
+}
+
+fn child_path<'a>(path: &[&'a str], name: &'a str) -> Vec<&'a str> {
+    let mut path = path.to_vec();
+    path.push(name);
+    path
+}
+
+fn write_header(out: &mut String, path: &[&str]) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    writeln!(out, "[{}]", path.iter().map(|name| key(name)).collect::<Vec<_>>().join(".")).unwrap();
+}
+
+/// Returns `true` if the definition has no profile-wide options and can be written as an array.
+fn is_plain(definition: &ConfigDefinition) -> bool {
+    definition.params.is_empty() && !definition.multiplex && definition.description.is_none() &&
+        definition.tags.is_empty() && definition.env.is_none() && definition.confirm.is_none() &&
+        !definition.record && definition.record_dir.is_none()
+}
+
+fn write_definition(out: &mut String, definition: &ConfigDefinition) {
+    if let Some(ref description) = definition.description {
+        writeln!(out, "description = {}", quote(description)).unwrap();
+    }
+    if let Some(ref env) = definition.env {
+        writeln!(out, "env = {}", quote(env)).unwrap();
+    }
+    if !definition.tags.is_empty() {
+        writeln!(out, "tags = {}", strings(&definition.tags)).unwrap();
+    }
+    if let Some(confirm) = definition.confirm {
+        writeln!(out, "confirm = {}", confirm).unwrap();
+    }
+    if definition.multiplex {
+        writeln!(out, "multiplex = true").unwrap();
+    }
+    if definition.record {
+        writeln!(out, "record = true").unwrap();
+    }
+    if let Some(ref dir) = definition.record_dir {
+        writeln!(out, "record_dir = {}", quote(dir)).unwrap();
+    }
+    if !definition.params.is_empty() {
+        let params: Vec<_> = definition.params.iter()
+            .map(|(name, param)| format!("{} = {}", key(name), param_value(param)))
+            .collect();
+        writeln!(out, "params = {{ {} }}", params.join(", ")).unwrap();
+    }
+    writeln!(out, "chain = {}", chain(&definition.chain)).unwrap();
+}
+
+fn param_value(param: &Param) -> String {
+    let mut fields = Vec::new();
+    fields.extend(param.default.as_ref().map(|default| format!("default = {}", quote(default))));
+    if !param.choices.is_empty() {
+        fields.push(format!("choices = {}", strings(&param.choices)));
+    }
+    inline_table(fields)
+}
+
+/// Puts a single jump on the same line and every jump of a longer chain on its own line.
+///
+/// TOML arrays cannot mix strings and tables, so jumps are only written as strings if all of them can be.
+fn chain(chain: &[SingleJump]) -> String {
+    let shorthand = chain.iter().all(fits_shorthand);
+    match chain.len() {
+        0 => "[]".into(),
+        1 => format!("[{}]", jump(&chain[0], shorthand)),
+        _ => {
+            let mut out = String::from("[\n");
+            for j in chain {
+                writeln!(out, "    {},", jump(j, shorthand)).unwrap();
+            }
+            out.push(']');
+            out
+        }
+    }
+}
+
+/// Returns `true` if the jump can be written as a `user@host:port:key` string.
+fn fits_shorthand(jump: &SingleJump) -> bool {
+    let has_options = jump.key_location.is_some() || jump.tunnel != State::Unset || jump.verbose ||
+        jump.agent_passthrough != State::Unset || jump.no_command != State::Unset || jump.transport.is_some() ||
+        jump.ssh_path.is_some() || !jump.set_env.is_empty() || !jump.send_env.is_empty();
+    let user_fits = jump.user.as_ref().map(|u| !u.contains(|c| c == ':' || c == '@')).unwrap_or(true);
+    let key_fits = jump.key.as_ref().map(|k| !k.contains(':')).unwrap_or(true);
+
+    !has_options && user_fits && key_fits
+}
+
+fn jump(jump: &SingleJump, shorthand: bool) -> String {
+    if shorthand {
+        let mut host = String::new();
+        if let Some(ref user) = jump.user {
+            host.push_str(user);
+            host.push('@');
+        }
+        host.push_str(&jump.host);
+        match (jump.port, &jump.key) {
+            (port, &Some(ref key)) =>
+                write!(host, ":{}:{}", port.map(|p| p.to_string()).unwrap_or_default(), key).unwrap(),
+            (Some(port), &None) => write!(host, ":{}", port).unwrap(),
+            (None, &None) => {}
+        }
+        return quote(&escape_vars(&host));
+    }
+
+    let mut fields = vec![format!("host = {}", quote(&escape_vars(&jump.host)))];
+    fields.extend(jump.port.map(|port| format!("port = {}", port)));
+    fields.extend(jump.user.as_ref().map(|user| format!("user = {}", quote(&escape_vars(user)))));
+    fields.extend(jump.key.as_ref().map(|key| format!("key = {}", quote(&escape_vars(key)))));
+    fields.extend(jump.key_location.map(|l| format!("key_location = {}", quote(l.name()))));
+    match jump.tunnel {
+        State::Enabled(ref t) => fields.push(format!("tunnel = {}", tunnel(t))),
+        State::Disabled => fields.push("tunnel = false".into()),
+        State::Unset => {}
+    }
+    if jump.verbose {
+        fields.push("verbose = true".into());
+    }
+    fields.extend(flag("agent_passthrough", &jump.agent_passthrough));
+    fields.extend(flag("no_command", &jump.no_command));
+    fields.extend(jump.transport.map(|t| format!("transport = {}", quote(t.name()))));
+    fields.extend(jump.ssh_path.as_ref().map(|path| format!("ssh_path = {}", quote(&escape_vars(path)))));
+    if !jump.set_env.is_empty() {
+        let vars: Vec<_> = jump.set_env.iter()
+            .map(|(name, value)| format!("{} = {}", key(name), quote(&escape_vars(value))))
+            .collect();
+        fields.push(format!("set_env = {}", inline_table(vars)));
+    }
+    if !jump.send_env.is_empty() {
+        fields.push(format!("send_env = {}", strings(&jump.send_env)));
+    }
+    inline_table(fields)
+}
+
+fn flag(name: &str, state: &State<()>) -> Option<String> {
+    match *state {
+        State::Enabled(()) => Some(format!("{} = true", name)),
+        State::Disabled => Some(format!("{} = false", name)),
+        State::Unset => None,
+    }
+}
+
+fn tunnel(tunnel: &Tunnel) -> String {
+    if let Tunnel { local_port: Some(port), local_host: None, remote_port: None, remote_host: None } = *tunnel {
+        return port.to_string();
+    }
+
+    // The string form cannot contain separators in hosts or blank hosts, and needs a port
+    let host_fits = |host: &Option<String>| match *host {
+        Some(ref host) => !host.trim().is_empty() && !host.contains(|c| c == ':' || c == '|'),
+        None => true,
+    };
+    let has_port = tunnel.local_port.is_some() || tunnel.remote_port.is_some();
+
+    if has_port && host_fits(&tunnel.local_host) && host_fits(&tunnel.remote_host) {
+        let side = |host: &Option<String>, port: Option<u16>| format!(
+            "{}:{}",
+            host.as_ref().map(|h| h.as_str()).unwrap_or(""),
+            port.map(|p| p.to_string()).unwrap_or_default()
+        );
+        let spec = format!(
+            "{}|{}",
+            side(&tunnel.local_host, tunnel.local_port),
+            side(&tunnel.remote_host, tunnel.remote_port)
+        );
+        return quote(&escape_vars(&spec));
+    }
+
+    let mut fields = Vec::new();
+    fields.extend(tunnel.local_port.map(|port| format!("local_port = {}", port)));
+    fields.extend(tunnel.local_host.as_ref().map(|host| format!("local_host = {}", quote(&escape_vars(host)))));
+    fields.extend(tunnel.remote_port.map(|port| format!("remote_port = {}", port)));
+    fields.extend(tunnel.remote_host.as_ref().map(|host| format!("remote_host = {}", quote(&escape_vars(host)))));
+    inline_table(fields)
+}
+
+fn inline_table(fields: Vec<String>) -> String {
+    if fields.is_empty() {
+        "{}".into()
+    } else {
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+fn strings(values: &[String]) -> String {
+    format!("[{}]", values.iter().map(|v| quote(v)).collect::<Vec<_>>().join(", "))
+}
+
+/// Protects `$` in values where the loader expands variable references.
+fn escape_vars(s: &str) -> String {
+    s.replace('$', "$$")
+}
+
+fn key(name: &str) -> String {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        name.into()
+    } else {
+        quote(name)
+    }
+}
+
+/// Writes a TOML basic string.
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => write!(out, "\\u{:04X}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+    use super::*;
+    use config::{self, KeyLocation, Transport};
+
+    #[test]
+    fn test_to_string() {
+        let config = config::load_from_string(r#"
+be = ["user@be.example.com:2244:~/.ssh/be.pem"]
+odd = [{ host = "odd", user = "a:b", key = "C:\\key" }]
+
+[work.db]
+description = "Main \"database\""
+tags = ["db"]
+chain = [
+  { host = "$$gate" },
+  { host = "db", tunnel = 5432 },
+  { host = "replica", tunnel = ":15432|db.local:5432", agent_passthrough = false },
+]
+
+[work.web.eu]
+app = [{ host = "app", port = 2222, transport = "autossh" }]
+"#).unwrap();
+
+        assert_eq!(to_string(&config).unwrap(), r#"be = ["user@be.example.com:2244:~/.ssh/be.pem"]
+odd = [{ host = "odd", user = "a:b", key = "C:\\key" }]
+
+[work.db]
+description = "Main \"database\""
+tags = ["db"]
+chain = [
+    { host = "$$gate" },
+    { host = "db", tunnel = 5432 },
+    { host = "replica", tunnel = ":15432|db.local:5432", agent_passthrough = false },
+]
+
+[work.web.eu]
+app = [{ host = "app", port = 2222, transport = "autossh" }]
+"#);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        fn prop(config: Config) -> bool {
+            match to_string(&config) {
+                Ok(s) => config::load_from_string(&s).ok() == Some(config),
+                Err(_) => has_reserved_names(&config.root, true),
+            }
+        }
+        QuickCheck::new().quickcheck(prop as fn(Config) -> bool);
+    }
+
+    const NAME: &[char] = &['a', 'b', '_', '-', '.', ' ', '"', 'é'];
+    const RESERVED: &[&str] = &["chain", "_inventory", "vars"];
+    const HOST: &[char] = &['a', 'b', '.', '-', '$', 'é'];
+    const VALUE: &[char] = &['a', 'b', ':', '@', '|', ' ', '$', '"', '\\', '~', '/'];
+    const VAR_START: &[char] = &['A', 'b', '_'];
+    const VAR: &[char] = &['A', 'b', '_', '1'];
+    const PATTERN: &[char] = &['A', 'B', '_', '*', '?'];
+    const TEXT: &[char] = &['a', 'b', ' ', '$', '{', '}', '"', '\\', '\n', '\t', 'é'];
+
+    fn string<G: Gen>(g: &mut G, alphabet: &[char], min: usize) -> String {
+        let len = g.gen_range(min, 6);
+        (0..len).map(|_| *g.choose(alphabet).unwrap()).collect()
+    }
+
+    fn option<G: Gen, T, F: FnOnce(&mut G) -> T>(g: &mut G, f: F) -> Option<T> {
+        if g.gen() { Some(f(g)) } else { None }
+    }
+
+    fn state<G: Gen, T, F: FnOnce(&mut G) -> T>(g: &mut G, f: F) -> State<T> {
+        match g.gen_range(0, 3) {
+            0 => State::Enabled(f(g)),
+            1 => State::Disabled,
+            _ => State::Unset,
+        }
+    }
+
+    fn gen_jump<G: Gen>(g: &mut G, first: bool) -> SingleJump {
+        let transport = *g.choose(&[None, Some(Transport::Ssh), Some(Transport::Autossh)]).unwrap();
+        SingleJump {
+            host: string(g, HOST, 1),
+            port: option(g, |g| g.gen()),
+            user: option(g, |g| string(g, VALUE, 0)),
+            key: option(g, |g| string(g, VALUE, 0)),
+            // Local keys on later jumps restrict the jumps before them, so only valid locations are made
+            key_location: option(g, |_| if first { KeyLocation::Local } else { KeyLocation::Remote }),
+            tunnel: state(g, |g| Tunnel {
+                local_port: option(g, |g| g.gen()),
+                local_host: option(g, |g| string(g, VALUE, 0)),
+                remote_port: option(g, |g| g.gen()),
+                remote_host: option(g, |g| string(g, VALUE, 0)),
+            }),
+            verbose: g.gen(),
+            agent_passthrough: state(g, |_| ()),
+            no_command: state(g, |_| ()),
+            transport,
+            // autossh does not support custom ssh paths
+            ssh_path: match transport {
+                Some(Transport::Autossh) => None,
+                _ => option(g, |g| string(g, VALUE, 1)),
+            },
+            set_env: (0..g.gen_range(0, 3))
+                .map(|_| (format!("{}{}", g.choose(VAR_START).unwrap(), string(g, VAR, 0)), string(g, VALUE, 0)))
+                .collect(),
+            send_env: (0..g.gen_range(0, 3)).map(|_| string(g, PATTERN, 1)).collect(),
+        }
+    }
+
+    fn gen_definition<G: Gen>(g: &mut G) -> ConfigDefinition {
+        let mut params = BTreeMap::new();
+        for _ in 0..g.gen_range(0, 3) {
+            let choices: Vec<_> = (0..g.gen_range(0, 3)).map(|_| string(g, TEXT, 0)).collect();
+            let default = match g.choose(&choices).cloned() {
+                Some(choice) => option(g, |_| choice),
+                None => option(g, |g| string(g, TEXT, 0)),
+            };
+            params.insert(string(g, NAME, 1), Param { default, choices });
+        }
+
+        ConfigDefinition {
+            chain: (0..g.gen_range(1, 4)).map(|i| gen_jump(g, i == 0)).collect(),
+            params,
+            multiplex: g.gen_weighted_bool(4),
+            description: option(g, |g| string(g, TEXT, 0)),
+            tags: (0..g.gen_range(0, 3)).map(|_| string(g, TEXT, 0)).collect(),
+            env: option(g, |g| string(g, TEXT, 0)),
+            confirm: option(g, |g| g.gen()),
+            record: g.gen_weighted_bool(4),
+            record_dir: option(g, |g| string(g, TEXT, 0)),
+        }
+    }
+
+    fn gen_group<G: Gen>(g: &mut G, depth: usize) -> ConfigGroup {
+        let mut definitions = BTreeMap::new();
+        for _ in 0..g.gen_range(0, 4) {
+            let item = if depth < 3 && g.gen_weighted_bool(3) {
+                ConfigItem::Subgroup(gen_group(g, depth + 1))
+            } else if g.gen() {
+                ConfigItem::Definition(ConfigDefinition { chain: vec![gen_jump(g, true)], ..ConfigDefinition::default() })
+            } else {
+                ConfigItem::Definition(gen_definition(g))
+            };
+            let name = if g.gen_weighted_bool(10) { g.choose(RESERVED).unwrap().to_string() } else { string(g, NAME, 1) };
+            definitions.insert(name, item);
+        }
+        ConfigGroup { definitions }
+    }
+
+    fn has_reserved_names(group: &ConfigGroup, root: bool) -> bool {
+        group.definitions.iter().any(|(name, item)| {
+            (RESERVED.contains(&name.as_str()) && (root || name != "vars")) || match *item {
+                ConfigItem::Subgroup(ref subgroup) => has_reserved_names(subgroup, false),
+                ConfigItem::Definition(_) => false,
+            }
+        })
+    }
+
+    impl Arbitrary for Config {
+        fn arbitrary<G: Gen>(g: &mut G) -> Config {
+            Config { root: gen_group(g, 0) }
+        }
+    }
+}
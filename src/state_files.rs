@@ -0,0 +1,46 @@
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Loads a state file with one entry per line; a missing file has no entries.
+pub fn load_lines(path: &Path) -> io::Result<Vec<String>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut lines = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+/// Replaces a state file with the entries, one per line; the directory must exist.
+pub fn save_lines(path: &Path, lines: &[String]) -> io::Result<()> {
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+
+    // Write to a temporary file first so that a crash does not lose all entries
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Reports a failure to update the bookkeeping done alongside a connection, like its history.
+///
+/// This state is only informational, so losing it must never prevent the connection; errors are
+/// printed and otherwise ignored.
+pub fn report<E: Display>(result: Result<(), E>, what: &str, path: &Path) {
+    if let Err(e) = result {
+        eprintln!("Failed to record {} in {}: {}", what, path.display(), e);
+    }
+}
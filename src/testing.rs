@@ -0,0 +1,49 @@
+//! Fixtures shared by tests.
+//!
+//! The module is included both by the library and by the binary, each using only some of the fixtures.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A scratch directory which is removed with its contents when dropped, also when a test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `sshc-<name>-test-<pid>` in the system temporary directory.
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("sshc-{}-test-{}", name, process::id()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+
+    /// Writes a file with the given permissions and returns its path.
+    pub fn write(&self, name: &str, contents: &str, mode: u32) -> PathBuf {
+        let path = self.join(name);
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    /// Writes an executable shell script with the given body and returns its path.
+    pub fn script(&self, name: &str, body: &str) -> PathBuf {
+        self.write(name, &format!("#!/bin/sh\n{}", body), 0o755)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
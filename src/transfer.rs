@@ -0,0 +1,148 @@
+use config::{ConfigDefinition, ConfigGroup, KeyLocation};
+use execution::{self, shell_join};
+
+/// One side of a transfer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Location {
+    Local(String),
+    Remote { profile: String, path: String },
+}
+
+impl Location {
+    /// Parses `profile:path` as a remote location if the profile exists, and as a local path otherwise.
+    pub fn parse(root: &ConfigGroup, s: &str) -> Location {
+        let mut parts = s.splitn(2, ':');
+        if let (profile, Some(path)) = (parts.next().unwrap(), parts.next()) {
+            if root.get_definition(profile).is_ok() {
+                return Location::Remote { profile: profile.into(), path: path.into() };
+            }
+        }
+        Location::Local(s.into())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Tool {
+    Scp,
+    Rsync,
+}
+
+/// Builds the command copying `source` to `destination`, exactly one of which is remote.
+///
+/// The remote side is the last jump of the definition; all previous jumps are connected through
+/// a `ProxyCommand`, so keys of all jumps must be available locally. Keys with
+/// `key_location = "remote"` are refused. Every jump is connected with its `ssh_path`, or plain
+/// ssh, whatever its transport.
+pub fn command(definition: &ConfigDefinition,
+               source: &Location,
+               destination: &Location,
+               tool: Tool,
+               recursive: bool) -> Result<Vec<String>, String> {
+    let target = match definition.chain.last() {
+        Some(target) => target,
+        None => return Err("the profile has no jumps".into()),
+    };
+    let remote_key = definition.chain.iter()
+        .position(|jump| jump.key.is_some() && jump.key_location == Some(KeyLocation::Remote));
+    if let Some(idx) = remote_key {
+        return Err(format!("the key of jump {} is on a remote host, but all jumps are connected from the local machine", idx + 1));
+    }
+
+    let remote = |path: &str| format!("{}:{}", target.destination(), path);
+    let path = |location: &Location| match *location {
+        Location::Local(ref path) => path.clone(),
+        Location::Remote { ref path, .. } => remote(path),
+    };
+
+    let mut command = Vec::new();
+    match tool {
+        Tool::Scp => {
+            command.push("scp".into());
+            if recursive {
+                command.push("-r".into());
+            }
+            if let Some(ref ssh_path) = target.ssh_path {
+                command.push("-S".into());
+                command.push(ssh_path.clone());
+            }
+            command.extend(execution::direct_options(&definition.chain, "-P"));
+        }
+        Tool::Rsync => {
+            let mut ssh = vec![target.ssh_path.clone().unwrap_or_else(|| "ssh".into())];
+            ssh.extend(execution::direct_options(&definition.chain, "-p"));
+
+            command.push("rsync".into());
+            command.push(if recursive { "-a" } else { "-t" }.into());
+            command.push("-e".into());
+            command.push(shell_join(&ssh));
+        }
+    }
+    command.push(path(source));
+    command.push(path(destination));
+
+    Ok(command)
+}
+
+/// Joins the command into a line which can be pasted into a shell.
+pub fn command_line(command: &[String]) -> String {
+    shell_join(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::SingleJump;
+
+    fn jump(host: &str, port: Option<u16>, key: Option<&str>) -> SingleJump {
+        SingleJump {
+            port,
+            user: Some("user".into()),
+            key: key.map(Into::into),
+            ..SingleJump::new(host.into())
+        }
+    }
+
+    #[test]
+    fn test_command() {
+        let definition = ConfigDefinition {
+            chain: vec![
+                jump("bastion", Some(2222), Some("~/.ssh/bastion.pem")),
+                jump("gateway", None, None),
+                jump("app", Some(2200), Some("~/.ssh/app key.pem")),
+            ],
+            ..ConfigDefinition::default()
+        };
+        let local = Location::Local("dump.sql".into());
+        let remote = Location::Remote { profile: "app".into(), path: "/tmp/".into() };
+
+        assert_eq!(
+            command_line(&command(&definition, &local, &remote, Tool::Scp, false).unwrap()),
+            "scp -P 2200 -i '~/.ssh/app key.pem' \
+             -o 'ProxyCommand=ssh -o '\\''ProxyCommand=ssh -p 2222 -i ~/.ssh/bastion.pem -W gateway:22 user@bastion'\\'' \
+             -W app:2200 user@gateway' \
+             dump.sql user@app:/tmp/"
+        );
+
+        assert_eq!(
+            command(&definition, &remote, &local, Tool::Rsync, true).unwrap()[..3].to_vec(),
+            vec!["rsync".to_owned(), "-a".into(), "-e".into()]
+        );
+
+        let mut custom_ssh = definition.clone();
+        custom_ssh.chain[2].ssh_path = Some("/opt/ssh/bin/ssh".into());
+        let scp = command(&custom_ssh, &local, &remote, Tool::Scp, false).unwrap();
+        assert_eq!(scp[1..5].to_vec(), vec!["-S", "/opt/ssh/bin/ssh", "-P", "2200"]);
+        let rsync = command(&custom_ssh, &local, &remote, Tool::Rsync, false).unwrap();
+        assert!(rsync[3].starts_with("/opt/ssh/bin/ssh -p 2200 "));
+
+        let empty = ConfigDefinition::default();
+        assert_eq!(command(&empty, &local, &remote, Tool::Scp, false), Err("the profile has no jumps".into()));
+
+        let mut remote_key = definition.clone();
+        remote_key.chain[2].key_location = Some(KeyLocation::Remote);
+        assert_eq!(
+            command(&remote_key, &local, &remote, Tool::Rsync, false),
+            Err("the key of jump 3 is on a remote host, but all jumps are connected from the local machine".into())
+        );
+    }
+}
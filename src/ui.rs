@@ -1,61 +1,265 @@
 use std::rc::Rc;
 use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
 
-use cursive::Cursive;
-use cursive::event::{Key, EventResult};
+use cursive::{Cursive, Printer};
+use cursive::event::{Event, Key, EventResult};
+use cursive::theme::{BaseColor, Color, ColorStyle};
 use cursive::traits::*;
-use cursive::views::{SelectView, OnEventView, Dialog, LinearLayout, TextView, DummyView};
+use cursive::vec::Vec2;
+use cursive::views::{SelectView, OnEventView, Dialog, LinearLayout, TextView, DummyView, EditView, ListView};
 use either::Either;
 use itertools::Itertools;
 
-use config::{Config, ConfigItem, ConfigDefinition, ConfigGroup};
+use config::{self, Config, ConfigItem, ConfigDefinition, ConfigGroup, SingleJump};
+use edit::{ConfigEditor, JumpFields};
 use execution::Execution;
+use history;
+use pins;
+use preview;
+use keys::{self, KeyCheck};
+use launch;
+use record;
+use mux;
+
+/// Number of profiles shown in the "Recent" pseudo-group.
+const RECENT_LIMIT: usize = 10;
 
 struct State {
-    config: Config,
+    config: RefCell<Config>,
+    config_path: PathBuf,
+    /// Modification time of the config file when it was loaded
+    config_mtime: Cell<Option<SystemTime>>,
     path: RefCell<Vec<String>>,
+    pseudo_group: Cell<Option<PseudoGroup>>,
+    /// Pseudo-group the current profile was opened from, which going up returns to
+    origin: Cell<Option<PseudoGroup>>,
+    /// Query of the "Search" pseudo-group
+    filter: RefCell<String>,
+    params: RefCell<BTreeMap<String, String>>,
     execute: Cell<bool>,
+    /// Set when the menu is closed to open the config in an external editor
+    edit_config: Cell<bool>,
+    /// Whether the list of profiles is on the top, so that it can be refreshed
+    list_active: Cell<bool>,
+    /// Key checks of the opened profile with the chain they were made for, since checking runs
+    /// ssh-keygen and ssh-add
+    key_checks: RefCell<Option<(Vec<SingleJump>, Vec<KeyCheck>)>>,
+}
+
+/// Value of an item in the list of profiles.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Selection {
+    /// Go up one level
+    Up,
+    /// A group or a profile in the current group
+    Item(String),
+    /// A pseudo-group shown at the root level
+    Pseudo(PseudoGroup),
+    /// A profile in a pseudo-group, identified by its full path
+    Profile(Vec<String>),
+}
+
+/// A group which is not defined in the config but is computed from other sources.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum PseudoGroup {
+    Pinned,
+    Recent,
+    Search,
+}
+
+impl PseudoGroup {
+    /// Returns pseudo-groups shown at the root level.
+    fn all() -> &'static [PseudoGroup] {
+        &[PseudoGroup::Pinned, PseudoGroup::Recent]
+    }
+
+    fn title(&self) -> &'static str {
+        match *self {
+            PseudoGroup::Pinned => "Pinned",
+            PseudoGroup::Recent => "Recent",
+            PseudoGroup::Search => "Search",
+        }
+    }
+
+    /// Returns full paths of the profiles in this group which exist in the config.
+    fn profiles(&self, state: &State, saved: &Saved) -> Vec<Vec<String>> {
+        let config = state.config.borrow();
+        let paths = match *self {
+            PseudoGroup::Pinned => saved.pinned.clone(),
+            PseudoGroup::Recent => saved.recent.clone(),
+            PseudoGroup::Search => config.root.iter_definitions().into_iter()
+                .filter(|&(ref path, definition)| matches_filter(path, definition, &state.filter.borrow()))
+                .map(|(path, _)| path)
+                .collect(),
+        };
+
+        paths.into_iter()
+            .filter(|path| config.root.get_definition(path).is_ok())
+            .map(|path| path.split('.').map(Into::into).collect())
+            .collect()
+    }
+}
+
+/// Pinned and recent profiles, read from their files once for every rendering of the list.
+struct Saved {
+    pinned: Vec<String>,
+    recent: Vec<String>,
+}
+
+impl Saved {
+    fn load() -> Saved {
+        let entries = history::load().unwrap_or_default();
+        Saved {
+            pinned: pins::load().unwrap_or_default(),
+            recent: history::recent_profiles(&entries, RECENT_LIMIT),
+        }
+    }
 }
 
 impl State {
-    fn current_item(&self) -> Either<&ConfigDefinition, &ConfigGroup> {
-        let mut current_group = &self.config.root;
-        for path_item in self.path.borrow().iter() {
-            match current_group.definitions[path_item] {
-                ConfigItem::Subgroup(ref group) => current_group = group,
-                ConfigItem::Definition(ref definition) => return Either::Left(definition),
+    /// Returns a copy of the current item, since the config may be replaced when it is edited.
+    fn current_item(&self) -> Either<ConfigDefinition, ConfigGroup> {
+        let config = self.config.borrow();
+        let path = self.path.borrow();
+        if path.is_empty() {
+            return Either::Right(config.root.clone());
+        }
+
+        // `replace_config` keeps the path valid, so the root is only a safety net
+        match config.root.get_path(&path[..]) {
+            Ok(&ConfigItem::Definition(ref definition)) => Either::Left(definition.clone()),
+            Ok(&ConfigItem::Subgroup(ref group)) => Either::Right(group.clone()),
+            Err(_) => Either::Right(config.root.clone()),
+        }
+    }
+
+    /// Replaces the config after it was reloaded or edited.
+    ///
+    /// The current path is kept if it still exists; otherwise the closest existing group is used.
+    fn replace_config(&self, config: Config) {
+        *self.config.borrow_mut() = config;
+        self.config_mtime.set(modified_time(&self.config_path));
+
+        let mut path = self.path.borrow_mut();
+        let existing = {
+            let config = self.config.borrow();
+            (1..path.len() + 1)
+                .take_while(|&n| config.root.get_path(&path[..n]).map(ConfigItem::is_group).unwrap_or(false))
+                .count()
+        };
+        path.truncate(existing);
+    }
+
+    /// Returns the key checks of the chain, reusing the ones made since the profile was opened.
+    fn key_checks(&self, chain: &[SingleJump]) -> Vec<KeyCheck> {
+        let mut cached = self.key_checks.borrow_mut();
+        if let Some((ref checked, ref checks)) = *cached {
+            if checked[..] == chain[..] {
+                return checks.clone();
             }
         }
-        Either::Right(current_group)
+
+        let checks = keys::check_chain(chain);
+        *cached = Some((chain.to_vec(), checks.clone()));
+        checks
+    }
+
+    /// Returns the full path of the selected profile or group.
+    fn selected_path(&self, selection: &Selection) -> Option<String> {
+        match *selection {
+            Selection::Item(ref name) => Some(self.path.borrow().iter().chain(Some(name)).join(".")),
+            Selection::Profile(ref path) => Some(path.iter().join(".")),
+            _ => None,
+        }
+    }
+
+    /// Returns the full path of the selected item if it is a profile.
+    fn selected_profile(&self, selection: &Selection) -> Option<String> {
+        let path = self.selected_path(selection)?;
+        if self.config.borrow().root.get_definition(&path).is_ok() {
+            Some(path)
+        } else {
+            None
+        }
     }
 }
 
-pub fn run(config  : Config,
-           dry_run : bool) {
+pub fn run(config      : Config,
+           config_path : &Path,
+           dry_run     : bool) {
     let state = Rc::new(State {
-        config,
+        config: RefCell::new(config),
+        config_path: config_path.into(),
+        config_mtime: Cell::new(modified_time(config_path)),
         path: RefCell::new(Vec::new()),
+        pseudo_group: Cell::new(None),
+        origin: Cell::new(None),
+        filter: RefCell::new(String::new()),
+        params: RefCell::new(BTreeMap::new()),
         execute: Cell::new(false),
+        edit_config: Cell::new(false),
+        list_active: Cell::new(false),
+        key_checks: RefCell::new(None),
     });
 
-    let mut siv = Cursive::new();
+    let mut error = None;
+    loop {
+        let mut siv = Cursive::new();
 
-    render_current_group(&mut siv,
-                         state.clone(),
-                         &state.config.root,
-                         None,
-                         dry_run);
+        render_current_list(&mut siv, state.clone(), None, dry_run);
+        if let Some(error) = error.take() {
+            show_list_error(&mut siv, state.clone(), error);
+        }
 
-    siv.run();
-    drop(siv);  // to dispose of backend messing with the terminal
+        // Check the config file for changes every second
+        siv.set_fps(1);
+        siv.add_global_callback(Event::Refresh, {
+            let state = state.clone();
+            move |s| reload_if_changed(s, state.clone(), dry_run)
+        });
+
+        siv.run();
+        drop(siv);  // to dispose of backend messing with the terminal
+
+        // Cursive cannot be suspended, so it is closed while the editor is running and started again
+        if !state.edit_config.get() {
+            break;
+        }
+        state.edit_config.set(false);
+        error = edit_externally(&state).err();
+    }
 
     if state.execute.get() {
         if let Either::Left(definition) = state.current_item() {
-            let mut e = Execution::from(definition.clone());
+            let definition = match definition.instantiate(&state.params.borrow()) {
+                Ok(definition) => definition,
+                Err(e) => {
+                    eprintln!("Invalid parameters for profile {}: {}", state.path.borrow().iter().join("."), e);
+                    ::std::process::exit(1);
+                }
+            };
+
+            if !dry_run && definition.multiplex {
+                mux::register(&state.path.borrow().iter().join("."));
+            }
+
+            if !dry_run && definition.record {
+                record::run_profile(&state.path.borrow().iter().join("."), definition);
+            }
+
+            history::record(&history::Entry::new(state.path.borrow().iter().join("."), dry_run, None));
+
             if dry_run {
-                println!("{}", e.command_line());
+                println!("{}", Execution::from(definition).command_line());
             } else {
-                e.run();
+                launch::run(definition);
             }
         }
     }
@@ -64,35 +268,69 @@ pub fn run(config  : Config,
 fn render_current_group(s             : &mut Cursive,
                         state         : Rc<State>,
                         group         : &ConfigGroup,
-                        last_selected : Option<String>,
+                        last_selected : Option<Selection>,
                         dry_run       : bool) {
-    s.pop_layer();
-
-    let mut select = SelectView::<String>::new()
-        .on_submit({
-            let state = state.clone();
-            move |s: &mut Cursive, name: &String|
-            handle_selection_submit(s,
-                                    state.clone(),
-                                    name,
-                                    dry_run)
-        });
-
     let mut items = Vec::new();
 
-    if !state.path.borrow().is_empty() {
-        items.push(("../".into(), "".into()));
+    if state.path.borrow().is_empty() {
+        let saved = Saved::load();
+        for &pseudo_group in PseudoGroup::all() {
+            if !pseudo_group.profiles(&state, &saved).is_empty() {
+                items.push((pseudo_group.title().to_owned() + "/", Selection::Pseudo(pseudo_group)));
+            }
+        }
+    } else {
+        items.push(("../".into(), Selection::Up));
     }
 
     let groups = group.definitions.iter().filter(|&(_, i)| i.is_group());
     let definitions = group.definitions.iter().filter(|&(_, i)| !i.is_group());
     for (k, _) in groups {
-        items.push((k.clone() + "/", k.clone()));
+        items.push((k.clone() + "/", Selection::Item(k.clone())));
     }
     for (k, _) in definitions {
-        items.push((k.clone(), k.clone()));
+        items.push((k.clone(), Selection::Item(k.clone())));
     }
 
+    let header = format_path(state.path.borrow().iter());
+    render_list(s, state, header, items, last_selected, dry_run);
+}
+
+fn render_pseudo_group(s             : &mut Cursive,
+                       state         : Rc<State>,
+                       pseudo_group  : PseudoGroup,
+                       last_selected : Option<Selection>,
+                       dry_run       : bool) {
+    let mut items = vec![("../".into(), Selection::Up)];
+    for path in pseudo_group.profiles(&state, &Saved::load()) {
+        items.push((path.iter().join("."), Selection::Profile(path)));
+    }
+
+    let header = match pseudo_group {
+        PseudoGroup::Search => format!("[{}: {}]", pseudo_group.title(), state.filter.borrow()),
+        _ => format!("[{}]", pseudo_group.title()),
+    };
+    render_list(s, state, header, items, last_selected, dry_run);
+}
+
+fn render_list(s             : &mut Cursive,
+               state         : Rc<State>,
+               header        : String,
+               items         : Vec<(String, Selection)>,
+               last_selected : Option<Selection>,
+               dry_run       : bool) {
+    s.pop_layer();
+
+    let mut select = SelectView::<Selection>::new()
+        .on_submit({
+            let state = state.clone();
+            move |s: &mut Cursive, selection: &Selection|
+            handle_selection_submit(s,
+                                    state.clone(),
+                                    selection,
+                                    dry_run)
+        });
+
     select.add_all(items.iter().cloned());
 
     if let Some(last_selected) = last_selected {
@@ -105,11 +343,97 @@ fn render_current_group(s             : &mut Cursive,
         .on_pre_event('e', {
             let state = state.clone();
             move |s| {
-                s.call_on_id("select", |sel: &mut SelectView| {
-                    let selection = (*sel.selection()).clone();
-                    state.path.borrow_mut().push(selection);
-                });
-                execute_definition(s, state.clone());
+                let selection = current_selection(s);
+                let previous_path = state.path.borrow().clone();
+                match selection {
+                    Some(Selection::Item(name)) => state.path.borrow_mut().push(name),
+                    Some(Selection::Profile(path)) => *state.path.borrow_mut() = path,
+                    _ => return,
+                }
+
+                match state.current_item() {
+                    Either::Left(definition) => {
+                        state.origin.set(state.pseudo_group.get());
+                        state.pseudo_group.set(None);
+                        if definition.params.is_empty() {
+                            state.params.borrow_mut().clear();
+                            execute_definition(s, state.clone(), dry_run);
+                        } else {
+                            render_params_form(s, state.clone(), &definition, dry_run);
+                        }
+                    }
+                    // Groups cannot be executed
+                    Either::Right(_) => *state.path.borrow_mut() = previous_path,
+                }
+            }
+        })
+        .on_pre_event('p', {
+            let state = state.clone();
+            move |s| update_pins(s, state.clone(), dry_run, |pinned, profile| { pins::toggle(pinned, profile); })
+        })
+        .on_pre_event('a', {
+            let state = state.clone();
+            move |s| {
+                // New profiles are added to the current group, or to the root in pseudo-groups
+                let group = match state.pseudo_group.get() {
+                    Some(_) => String::new(),
+                    None => state.path.borrow().iter().map(|p| format!("{}.", p)).collect(),
+                };
+                render_jumps_form(s, state.clone(), group, None, vec![JumpFields::default()], dry_run)
+            }
+        })
+        .on_pre_event('c', {
+            let state = state.clone();
+            move |s| {
+                let profile = match current_selection(s).and_then(|sel| state.selected_profile(&sel)) {
+                    Some(profile) => profile,
+                    None => return,
+                };
+                match ConfigEditor::open(&state.config_path).and_then(|mut editor| editor.jumps(&profile)) {
+                    Ok(jumps) => render_jumps_form(s, state.clone(), profile.clone(), Some(profile), jumps, dry_run),
+                    Err(e) => show_list_error(s, state.clone(), format!("Cannot edit {}: {}", profile, e)),
+                }
+            }
+        })
+        .on_pre_event('d', {
+            let state = state.clone();
+            move |s| if let Some(path) = current_selection(s).and_then(|sel| state.selected_path(&sel)) {
+                render_delete_form(s, state.clone(), path, dry_run)
+            }
+        })
+        .on_pre_event('r', {
+            let state = state.clone();
+            move |s| if let Some(path) = current_selection(s).and_then(|sel| state.selected_path(&sel)) {
+                render_rename_form(s, state.clone(), path, false, dry_run)
+            }
+        })
+        .on_pre_event('m', {
+            let state = state.clone();
+            move |s| if let Some(path) = current_selection(s).and_then(|sel| state.selected_path(&sel)) {
+                render_rename_form(s, state.clone(), path, true, dry_run)
+            }
+        })
+        .on_pre_event('E', {
+            let state = state.clone();
+            move |s| {
+                state.edit_config.set(true);
+                s.quit();
+            }
+        })
+        .on_pre_event('/', {
+            let state = state.clone();
+            move |s| render_filter_form(s, state.clone(), dry_run)
+        })
+        .on_pre_event('K', {
+            let state = state.clone();
+            move |s| if state.pseudo_group.get() == Some(PseudoGroup::Pinned) {
+                update_pins(s, state.clone(), dry_run, |pinned, profile| pins::shift(pinned, profile, true))
+            }
+        })
+        .on_pre_event('J', {
+            let state = state.clone();
+            move |s| if state.pseudo_group.get() == Some(PseudoGroup::Pinned) {
+                update_pins(s, state.clone(), dry_run, |pinned, profile| pins::shift(pinned, profile, false))
             }
         })
         .on_pre_event_inner('k', |s| {
@@ -123,7 +447,7 @@ fn render_current_group(s             : &mut Cursive,
         .with_id("select");
 
     let layout = LinearLayout::vertical()
-        .child(TextView::new(format_path(state.path.borrow().iter())))
+        .child(TextView::new(header))
         .child(DummyView)
         .child(select.fixed_size((80, 10)))
         .child(DummyView);
@@ -133,12 +457,391 @@ fn render_current_group(s             : &mut Cursive,
             .title("Profiles")
             .button("Quit", |s| s.quit())
     );
+    state.list_active.set(true);
+    // Keys are checked again when a profile is opened again, e.g. after a key was added to the agent
+    *state.key_checks.borrow_mut() = None;
 
     // up one level
     s.add_global_callback(Key::Esc, {
         let state = state.clone();
-        move |s| handle_selection_submit(s, state.clone(), "", dry_run)
+        move |s| handle_selection_submit(s, state.clone(), &Selection::Up, dry_run)
+    });
+}
+
+/// Applies the change to the pinned profiles if a profile is selected, and renders the list again.
+fn update_pins<F>(s: &mut Cursive, state: Rc<State>, dry_run: bool, change: F)
+    where F: FnOnce(&mut Vec<String>, &str)
+{
+    let selection = match current_selection(s) {
+        Some(selection) => selection,
+        None => return,
+    };
+    let profile = match state.selected_profile(&selection) {
+        Some(profile) => profile,
+        None => return,
+    };
+
+    let result = pins::load().and_then(|mut pinned| {
+        change(&mut pinned, &profile);
+        pins::save(&pinned)
+    });
+    if let Err(e) = result {
+        show_list_error(s, state, format!("Failed to save pinned profiles: {}", e));
+        return;
+    }
+
+    render_current_list(s, state, Some(selection), dry_run);
+}
+
+/// Shows an error over the profile list, which becomes active again once the error is dismissed.
+fn show_list_error(s: &mut Cursive, state: Rc<State>, message: String) {
+    state.list_active.set(false);
+    s.add_layer(
+        Dialog::text(message).button("Ok", move |s| {
+            s.pop_layer();
+            state.list_active.set(true);
+        })
+    );
+}
+
+/// Opens the config in `$VISUAL` or `$EDITOR` and reloads it.
+fn edit_externally(state: &State) -> Result<(), String> {
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".into());
+
+    // The editor may contain arguments, like `code --wait`, so it is run by the shell
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&state.config_path)
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", editor, e))?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", editor, status));
+    }
+
+    let config = config::load(&state.config_path)
+        .map_err(|e| format!("Failed to load configuration from {}: {}", state.config_path.display(), e))?;
+    state.replace_config(config);
+
+    Ok(())
+}
+
+/// Reloads the config and refreshes the list if the config file was modified.
+///
+/// Forms may refer to the old config, so while they are open the reload is postponed.
+fn reload_if_changed(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    let mtime = modified_time(&state.config_path);
+    if mtime == state.config_mtime.get() || !state.list_active.get() {
+        return;
+    }
+
+    match config::load(&state.config_path) {
+        Ok(config) => {
+            state.replace_config(config);
+            let selection = current_selection(s);
+            render_current_list(s, state, selection, dry_run);
+        }
+        Err(e) => {
+            // Do not report the same error every second
+            state.config_mtime.set(mtime);
+            let message = format!("Failed to reload configuration from {}: {}", state.config_path.display(), e);
+            show_list_error(s, state, message);
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn current_selection(s: &mut Cursive) -> Option<Selection> {
+    s.call_on_id("select", |sel: &mut SelectView<Selection>| (*sel.selection()).clone())
+}
+
+/// Renders the current group or pseudo-group again.
+fn render_current_list(s: &mut Cursive, state: Rc<State>, last_selected: Option<Selection>, dry_run: bool) {
+    match state.pseudo_group.get() {
+        Some(pseudo_group) => render_pseudo_group(s, state.clone(), pseudo_group, last_selected, dry_run),
+        None => if let Either::Right(group) = state.current_item() {
+            render_current_group(s, state.clone(), &group, last_selected, dry_run)
+        },
+    }
+}
+
+fn render_filter_form(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    // Esc is bound globally to going up, which would leave the form on the screen
+    s.add_global_callback(Key::Esc, {
+        let state = state.clone();
+        move |s| {
+            s.pop_layer();
+            render_current_list(s, state.clone(), None, dry_run);
+        }
     });
+
+    let edit = EditView::new()
+        .content(state.filter.borrow().clone())
+        .on_submit({
+            let state = state.clone();
+            move |s, query| {
+                s.pop_layer();
+                if query.trim().is_empty() {
+                    return render_current_list(s, state.clone(), None, dry_run);
+                }
+                *state.filter.borrow_mut() = query.into();
+                state.pseudo_group.set(Some(PseudoGroup::Search));
+                render_pseudo_group(s, state.clone(), PseudoGroup::Search, None, dry_run);
+            }
+        });
+
+    state.list_active.set(false);
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("Search names, descriptions, tags and environments:"))
+                .child(DummyView)
+                .child(edit.fixed_width(60))
+        )
+            .title("Filter")
+            .button("Cancel", move |s| {
+                s.pop_layer();
+                render_current_list(s, state.clone(), None, dry_run);
+            })
+    );
+}
+
+/// Closes the form on top of the list and renders the list again.
+fn close_form(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    s.pop_layer();
+    render_current_list(s, state, None, dry_run);
+}
+
+/// Shows a form with the jumps of a profile.
+///
+/// `name` is the initial full path of the profile, and `profile` is the path of the profile being
+/// edited, or `None` if a new profile is added.
+fn render_jumps_form(s       : &mut Cursive,
+                     state   : Rc<State>,
+                     name    : String,
+                     profile : Option<String>,
+                     jumps   : Vec<JumpFields>,
+                     dry_run : bool) {
+    // Esc is bound globally to going up, which would leave the form on the screen
+    s.add_global_callback(Key::Esc, {
+        let state = state.clone();
+        move |s| close_form(s, state.clone(), dry_run)
+    });
+
+    let mut list = ListView::new();
+    if profile.is_none() {
+        list.add_child("name", EditView::new().content(name.clone()).with_id("profile-name"));
+    }
+    for (idx, jump) in jumps.iter().enumerate() {
+        list.add_delimiter();
+        for &(field, value) in &[("host", &jump.host), ("port", &jump.port), ("user", &jump.user),
+                                 ("key", &jump.key), ("tunnel", &jump.tunnel)] {
+            list.add_child(field, EditView::new().content(value.clone()).with_id(jump_view_id(idx, field)));
+        }
+        for &(field, value) in &[("verbose", jump.verbose), ("agent_passthrough", jump.agent_passthrough),
+                                 ("no_command", jump.no_command)] {
+            let mut select = SelectView::new().popup();
+            select.add_all(vec![("default", None), ("yes", Some(true)), ("no", Some(false))]);
+            select.set_selection(match value { None => 0, Some(true) => 1, Some(false) => 2 });
+            list.add_child(field, select.with_id(jump_view_id(idx, field)));
+        }
+    }
+
+    let layout = LinearLayout::vertical()
+        .child(list.fixed_size((80, 20)))
+        .child(DummyView)
+        .child(TextView::new("").with_id("form-status"));
+
+    let title = match profile {
+        Some(ref profile) => format!("Edit profile {}", profile),
+        None => "New profile".into(),
+    };
+
+    let count = jumps.len();
+    let rerender = {
+        let state = state.clone();
+        let profile = profile.clone();
+        move |s: &mut Cursive, count: usize| {
+            let name = s.call_on_id("profile-name", |v: &mut EditView| (*v.get_content()).clone())
+                .unwrap_or_else(|| name.clone());
+            let mut jumps = read_jumps_form(s, count);
+            jumps.resize(count.max(1), JumpFields::default());
+            s.pop_layer();
+            render_jumps_form(s, state.clone(), name, profile.clone(), jumps, dry_run);
+        }
+    };
+    let rerender = Rc::new(rerender);
+
+    let mut dialog = Dialog::around(layout)
+        .title(title)
+        .button("Save", {
+            let state = state.clone();
+            move |s| {
+                let jumps = read_jumps_form(s, count);
+                let result = ConfigEditor::open(&state.config_path).and_then(|mut editor| {
+                    match profile {
+                        Some(ref profile) => editor.set_jumps(profile, &jumps)?,
+                        None => {
+                            let name = s.call_on_id("profile-name", |v: &mut EditView| (*v.get_content()).clone())
+                                .unwrap_or_default();
+                            editor.add(&name, &jumps)?
+                        }
+                    }
+                    editor.save()
+                });
+                match result {
+                    Ok(config) => {
+                        state.replace_config(config);
+                        close_form(s, state.clone(), dry_run);
+                    }
+                    Err(e) => set_form_status(s, &format!("Cannot save: {}", e)),
+                }
+            }
+        })
+        .button("Add jump", {
+            let rerender = rerender.clone();
+            move |s| rerender(s, count + 1)
+        });
+    // A profile needs at least one jump, and removing the last one would only clear its fields
+    if count > 1 {
+        dialog = dialog.button("Remove jump", {
+            let rerender = rerender.clone();
+            move |s| rerender(s, count - 1)
+        });
+    }
+    dialog = dialog.button("Cancel", {
+        let state = state.clone();
+        move |s| close_form(s, state.clone(), dry_run)
+    });
+
+    state.list_active.set(false);
+    s.add_layer(dialog);
+}
+
+fn jump_view_id(idx: usize, field: &str) -> String {
+    format!("jump-{}-{}", idx, field)
+}
+
+/// Reads the values of the first `count` jumps from the form; missing jumps are skipped.
+fn read_jumps_form(s: &mut Cursive, count: usize) -> Vec<JumpFields> {
+    let mut jumps = Vec::new();
+    for idx in 0..count {
+        let text = |s: &mut Cursive, field: &str|
+            s.call_on_id(&jump_view_id(idx, field), |v: &mut EditView| (*v.get_content()).clone());
+        let flag = |s: &mut Cursive, field: &str|
+            s.call_on_id(&jump_view_id(idx, field), |v: &mut SelectView<Option<bool>>| *v.selection()).unwrap_or(None);
+
+        let host = match text(s, "host") {
+            Some(host) => host,
+            None => break,
+        };
+        jumps.push(JumpFields {
+            host,
+            port: text(s, "port").unwrap_or_default(),
+            user: text(s, "user").unwrap_or_default(),
+            key: text(s, "key").unwrap_or_default(),
+            tunnel: text(s, "tunnel").unwrap_or_default(),
+            verbose: flag(s, "verbose"),
+            agent_passthrough: flag(s, "agent_passthrough"),
+            no_command: flag(s, "no_command"),
+        });
+    }
+    jumps
+}
+
+fn set_form_status(s: &mut Cursive, status: &str) {
+    s.call_on_id("form-status", |v: &mut TextView| v.set_content(status));
+}
+
+fn render_delete_form(s: &mut Cursive, state: Rc<State>, path: String, dry_run: bool) {
+    s.add_global_callback(Key::Esc, {
+        let state = state.clone();
+        move |s| close_form(s, state.clone(), dry_run)
+    });
+
+    let layout = LinearLayout::vertical()
+        .child(TextView::new(format!("Delete {} from the configuration file?", path)))
+        .child(TextView::new("").with_id("form-status"));
+
+    state.list_active.set(false);
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Delete")
+            .button("Delete", {
+                let state = state.clone();
+                move |s| {
+                    let result = ConfigEditor::open(&state.config_path).and_then(|mut editor| {
+                        editor.remove(&path)?;
+                        editor.save()
+                    });
+                    match result {
+                        Ok(config) => {
+                            state.replace_config(config);
+                            close_form(s, state.clone(), dry_run);
+                        }
+                        Err(e) => set_form_status(s, &format!("Cannot delete: {}", e)),
+                    }
+                }
+            })
+            .button("Cancel", move |s| close_form(s, state.clone(), dry_run))
+    );
+}
+
+/// Shows a form to rename the item within its group, or to move it to another path if `full` is set.
+fn render_rename_form(s: &mut Cursive, state: Rc<State>, path: String, full: bool, dry_run: bool) {
+    s.add_global_callback(Key::Esc, {
+        let state = state.clone();
+        move |s| close_form(s, state.clone(), dry_run)
+    });
+
+    let (prefix, name) = match path.rfind('.') {
+        Some(idx) if !full => (path[..idx + 1].to_owned(), path[idx + 1..].to_owned()),
+        _ => (String::new(), path.clone()),
+    };
+
+    let layout = LinearLayout::vertical()
+        .child(TextView::new(if full { "New full path, like group.subgroup.name:" } else { "New name:" }))
+        .child(EditView::new().content(name).with_id("new-name").fixed_width(60))
+        .child(TextView::new("").with_id("form-status"));
+
+    state.list_active.set(false);
+    s.add_layer(
+        Dialog::around(layout)
+            .title(format!("{} {}", if full { "Move" } else { "Rename" }, path))
+            .button("Ok", {
+                let state = state.clone();
+                move |s| {
+                    let new_name = s.call_on_id("new-name", |v: &mut EditView| (*v.get_content()).clone())
+                        .unwrap_or_default();
+                    let result = ConfigEditor::open(&state.config_path).and_then(|mut editor| {
+                        editor.rename(&path, &format!("{}{}", prefix, new_name))?;
+                        editor.save()
+                    });
+                    match result {
+                        Ok(config) => {
+                            state.replace_config(config);
+                            close_form(s, state.clone(), dry_run);
+                        }
+                        Err(e) => set_form_status(s, &format!("Cannot rename: {}", e)),
+                    }
+                }
+            })
+            .button("Cancel", move |s| close_form(s, state.clone(), dry_run))
+    );
+}
+
+/// Checks that every word of the query occurs in the path or the metadata of the profile, ignoring case.
+fn matches_filter(path: &str, definition: &ConfigDefinition, query: &str) -> bool {
+    let mut haystack = vec![path.to_lowercase()];
+    haystack.extend(definition.description.iter().map(|d| d.to_lowercase()));
+    haystack.extend(definition.env.iter().map(|e| e.to_lowercase()));
+    haystack.extend(definition.tags.iter().map(|t| t.to_lowercase()));
+
+    query.to_lowercase().split_whitespace().all(|word| haystack.iter().any(|h| h.contains(word)))
 }
 
 fn render_current_definition(s          : &mut Cursive,
@@ -147,7 +850,39 @@ fn render_current_definition(s          : &mut Cursive,
                              dry_run    : bool) {
     s.pop_layer();
 
-    let layout = LinearLayout::vertical()
+    let mut layout = LinearLayout::vertical();
+    if let Some(ref description) = definition.description {
+        layout.add_child(TextView::new(description.clone()));
+    }
+    if let Some(ref env) = definition.env {
+        layout.add_child(TextView::new(format!("Environment: {}", env)));
+    }
+    if !definition.tags.is_empty() {
+        layout.add_child(TextView::new(format!("Tags: {}", definition.tags.join(", "))));
+    }
+    if definition.description.is_some() || definition.env.is_some() || !definition.tags.is_empty() {
+        layout.add_child(DummyView);
+    }
+
+    layout.add_child(TextView::new("Jumps:"));
+    layout.add_child(TextView::new(preview::hops_table(definition).join("\n")));
+    layout.add_child(DummyView);
+
+    let tunnels = preview::tunnel_paths(definition);
+    if !tunnels.is_empty() {
+        layout.add_child(TextView::new("Tunnels:"));
+        layout.add_child(TextView::new(tunnels.join("\n")));
+        layout.add_child(DummyView);
+    }
+
+    let key_checks = state.key_checks(&definition.chain);
+    if !key_checks.is_empty() {
+        layout.add_child(TextView::new("Keys:"));
+        layout.add_child(TextView::new(key_checks.iter().map(KeyCheck::describe).join("\n")));
+        layout.add_child(DummyView);
+    }
+
+    let layout = layout
         .child(TextView::new(
             format!("Will {} the following command:",
                     if dry_run { "print" } else { "execute" })))
@@ -158,6 +893,7 @@ fn render_current_definition(s          : &mut Cursive,
             format!("Press Enter to {}, Esc to go back",
                     if dry_run { "print" } else { "run" })));
 
+    state.list_active.set(false);
     s.add_layer(
         Dialog::around(layout)
             .title(format!("Profile {}", state.path.borrow().iter().join(".")))
@@ -165,7 +901,7 @@ fn render_current_definition(s          : &mut Cursive,
 
     s.add_global_callback(Key::Enter, {
         let state = state.clone();
-        move |s| execute_definition(s, state.clone())
+        move |s| execute_definition(s, state.clone(), dry_run)
     });
 
     s.add_global_callback(Key::Esc, {
@@ -173,38 +909,135 @@ fn render_current_definition(s          : &mut Cursive,
         move |s: &mut Cursive| {
             s.add_global_callback(Key::Enter, |_| {});
             s.add_global_callback(Key::Esc, |_| {});
-            handle_selection_submit(s, state.clone(), "", dry_run)
+            handle_selection_submit(s, state.clone(), &Selection::Up, dry_run)
         }
     });
 }
 
-fn handle_selection_submit(s       : &mut Cursive,
-                           state   : Rc<State>,
-                           name    : &str,
-                           dry_run : bool) {
-    let last_selected = {
-        let mut path = state.path.borrow_mut();
-        if name.is_empty() {
+fn render_params_form(s          : &mut Cursive,
+                      state      : Rc<State>,
+                      definition : &ConfigDefinition,
+                      dry_run    : bool) {
+    s.pop_layer();
+
+    let mut list = ListView::new();
+    for (name, param) in &definition.params {
+        let value = state.params.borrow().get(name).cloned().or_else(|| param.default.clone());
+        if param.choices.is_empty() {
+            list.add_child(name, EditView::new()
+                .content(value.unwrap_or_default())
+                .with_id(param_view_id(name)));
+        } else {
+            let mut select = SelectView::new().popup();
+            select.add_all_str(param.choices.iter().cloned());
+            if let Some(idx) = value.and_then(|v| param.choices.iter().position(|c| *c == v)) {
+                select.set_selection(idx);
+            }
+            list.add_child(name, select.with_id(param_view_id(name)));
+        }
+    }
+
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("This profile has parameters:"))
+        .child(DummyView)
+        .child(list.fixed_width(80))
+        .child(DummyView);
+
+    state.list_active.set(false);
+    s.add_layer(
+        Dialog::around(layout)
+            .title(format!("Profile {}", state.path.borrow().iter().join(".")))
+            .button("Ok", {
+                let state = state.clone();
+                let definition = definition.clone();
+                move |s| {
+                    let mut values = BTreeMap::new();
+                    for (name, param) in &definition.params {
+                        let value = if param.choices.is_empty() {
+                            s.call_on_id(&param_view_id(name), |v: &mut EditView| (*v.get_content()).clone())
+                                .and_then(|v| if v.is_empty() { None } else { Some(v) })
+                        } else {
+                            s.call_on_id(&param_view_id(name), |v: &mut SelectView| (*v.selection()).clone())
+                        };
+                        if let Some(value) = value {
+                            values.insert(name.clone(), value);
+                        }
+                    }
+
+                    match definition.instantiate(&values) {
+                        Ok(instantiated) => {
+                            *state.params.borrow_mut() = values;
+                            render_current_definition(s, state.clone(), &instantiated, dry_run);
+                        }
+                        Err(e) => s.add_layer(Dialog::info(format!("Invalid parameters: {}", e))),
+                    }
+                }
+            })
+            .button("Back", move |s| handle_selection_submit(s, state.clone(), &Selection::Up, dry_run))
+    );
+}
+
+fn param_view_id(name: &str) -> String {
+    format!("param-{}", name)
+}
+
+fn handle_selection_submit(s         : &mut Cursive,
+                           state     : Rc<State>,
+                           selection : &Selection,
+                           dry_run   : bool) {
+    let last_selected = match *selection {
+        Selection::Up => if let Some(pseudo_group) = state.pseudo_group.get() {
+            state.pseudo_group.set(None);
+            Some(Selection::Pseudo(pseudo_group))
+        } else if let Some(origin) = state.origin.take() {
+            // A profile opened from a pseudo-group returns to it rather than to its own group
+            state.pseudo_group.set(Some(origin));
+            Some(Selection::Profile(mem::replace(&mut *state.path.borrow_mut(), Vec::new())))
+        } else {
+            let mut path = state.path.borrow_mut();
             if path.is_empty() {
                 s.quit();
                 None
             } else {
-                path.pop()
+                path.pop().map(Selection::Item)
             }
-        } else {
-            path.push(name.into());
+        },
+        Selection::Item(ref name) => {
+            state.path.borrow_mut().push(name.clone());
+            None
+        }
+        Selection::Pseudo(pseudo_group) => {
+            state.pseudo_group.set(Some(pseudo_group));
+            None
+        }
+        Selection::Profile(ref path) => {
+            state.origin.set(state.pseudo_group.get());
+            state.pseudo_group.set(None);
+            *state.path.borrow_mut() = path.clone();
             None
         }
     };
 
+    if let Some(pseudo_group) = state.pseudo_group.get() {
+        return render_pseudo_group(s, state.clone(), pseudo_group, last_selected, dry_run);
+    }
+
     match state.current_item() {
-        Either::Left(definition) => render_current_definition(s,
-                                                              state.clone(),
-                                                              definition,
-                                                              dry_run),
+        Either::Left(definition) => if definition.params.is_empty() {
+            state.params.borrow_mut().clear();
+            render_current_definition(s,
+                                      state.clone(),
+                                      &definition,
+                                      dry_run)
+        } else {
+            render_params_form(s,
+                               state.clone(),
+                               &definition,
+                               dry_run)
+        },
         Either::Right(group)     => render_current_group(s,
                                                          state.clone(),
-                                                         group,
+                                                         &group,
                                                          last_selected,
                                                          dry_run),
     }
@@ -225,7 +1058,89 @@ fn format_path<I: IntoIterator>(path: I) -> String where I::Item: AsRef<str> {
     result
 }
 
-fn execute_definition(s: &mut Cursive, state: Rc<State>) {
-    state.execute.set(true);
-    s.quit();
+fn execute_definition(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    let confirm = match state.current_item() {
+        Either::Left(definition) => !dry_run && definition.requires_confirmation(),
+        Either::Right(_) => false,
+    };
+
+    if confirm {
+        render_confirmation(s, state, dry_run);
+    } else {
+        state.execute.set(true);
+        s.quit();
+    }
+}
+
+/// Asks to type the profile name before connecting to a dangerous profile.
+fn render_confirmation(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    s.pop_layer();
+
+    let profile = state.path.borrow().iter().join(".");
+    let env = match state.current_item() {
+        Either::Left(ConfigDefinition { env: Some(ref env), .. }) => format!(" ({})", env),
+        _ => String::new(),
+    };
+
+    let layout = LinearLayout::vertical()
+        .child(Warning(format!(" WARNING: you are about to connect to {}{} ", profile, env)))
+        .child(DummyView)
+        .child(TextView::new("Type the profile name to continue:"))
+        .child(EditView::new().with_id("confirm-name").fixed_width(60))
+        .child(TextView::new("").with_id("confirm-status"));
+
+    state.list_active.set(false);
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Confirm connection")
+            .button("Connect", {
+                let state = state.clone();
+                move |s| check_confirmation(s, state.clone(), dry_run)
+            })
+            .button("Cancel", {
+                let state = state.clone();
+                move |s| cancel_confirmation(s, state.clone(), dry_run)
+            })
+    );
+
+    // Global callbacks take precedence over the dialog, so rebind them for its lifetime
+    s.add_global_callback(Key::Enter, {
+        let state = state.clone();
+        move |s| check_confirmation(s, state.clone(), dry_run)
+    });
+    s.add_global_callback(Key::Esc, move |s| cancel_confirmation(s, state.clone(), dry_run));
+}
+
+fn check_confirmation(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    let typed = s.call_on_id("confirm-name", |v: &mut EditView| (*v.get_content()).clone()).unwrap_or_default();
+    if typed == state.path.borrow().iter().join(".") {
+        state.execute.set(true);
+        s.quit();
+    } else {
+        s.call_on_id("confirm-name", |v: &mut EditView| v.set_content(""));
+        s.call_on_id("confirm-status", |v: &mut TextView| v.set_content("The name does not match, try again"));
+    }
+}
+
+fn cancel_confirmation(s: &mut Cursive, state: Rc<State>, dry_run: bool) {
+    s.add_global_callback(Key::Enter, |_| {});
+    s.add_global_callback(Key::Esc, |_| {});
+    handle_selection_submit(s, state, &Selection::Up, dry_run)
+}
+
+/// A single line of text drawn in white on red.
+struct Warning(String);
+
+impl View for Warning {
+    fn draw(&self, printer: &Printer) {
+        let style = ColorStyle::Custom {
+            front: Color::Light(BaseColor::White),
+            back: Color::Dark(BaseColor::Red),
+        };
+        printer.with_color(style, |printer| printer.print((0, 0), &self.0));
+    }
+
+    fn required_size(&mut self, _: Vec2) -> Vec2 {
+        Vec2::new(self.0.chars().count(), 1)
+    }
 }
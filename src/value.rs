@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+use serde_json;
+use serde_yaml;
+use toml;
+
+/// A configuration value, independent of the format of the file it was read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Datetime(String),
+    Array(Array),
+    Table(Table),
+    Null,
+}
+
+pub type Array = Vec<Value>;
+pub type Table = BTreeMap<String, Value>;
+
+impl Value {
+    /// Returns the name of the value type for error messages, in TOML terms for all formats.
+    pub fn type_str(&self) -> &'static str {
+        match *self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Datetime(_) => "datetime",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+            Value::Null => "null",
+        }
+    }
+}
+
+impl From<toml::Value> for Value {
+    fn from(value: toml::Value) -> Value {
+        match value {
+            toml::Value::String(s) => Value::String(s),
+            toml::Value::Integer(i) => Value::Integer(i),
+            toml::Value::Float(f) => Value::Float(f),
+            toml::Value::Boolean(b) => Value::Boolean(b),
+            toml::Value::Datetime(d) => Value::Datetime(d.to_string()),
+            toml::Value::Array(array) => Value::Array(array.into_iter().map(Value::from).collect()),
+            toml::Value::Table(table) => Value::Table(table.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Value {
+        match value {
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Value::Integer(i),
+                None => Value::Float(n.as_f64().unwrap_or(::std::f64::NAN)),
+            },
+            serde_json::Value::Bool(b) => Value::Boolean(b),
+            serde_json::Value::Array(array) => Value::Array(array.into_iter().map(Value::from).collect()),
+            serde_json::Value::Object(map) => Value::Table(map.into_iter().map(|(k, v)| (k, v.into())).collect()),
+            serde_json::Value::Null => Value::Null,
+        }
+    }
+}
+
+/// Converts a YAML value, failing on mapping keys which are not scalars.
+pub fn from_yaml(value: serde_yaml::Value) -> Result<Value, String> {
+    Ok(match value {
+        serde_yaml::Value::String(s) => Value::String(s),
+        serde_yaml::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Float(n.as_f64().unwrap_or(::std::f64::NAN)),
+        },
+        serde_yaml::Value::Bool(b) => Value::Boolean(b),
+        serde_yaml::Value::Sequence(sequence) =>
+            Value::Array(sequence.into_iter().map(from_yaml).collect::<Result<_, _>>()?),
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut table = Table::new();
+            for (k, v) in mapping {
+                let k = match k {
+                    serde_yaml::Value::String(s) => s,
+                    serde_yaml::Value::Number(n) => n.to_string(),
+                    serde_yaml::Value::Bool(b) => b.to_string(),
+                    other => return Err(format!(
+                        "invalid key: expected string, got {}", from_yaml(other)?.type_str()
+                    )),
+                };
+                table.insert(k, from_yaml(v)?);
+            }
+            Value::Table(table)
+        }
+        serde_yaml::Value::Null => Value::Null,
+    })
+}